@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+
 const INPUT: &'static str = include_str!("../inputs/day10.txt");
 
 pub(crate) fn run() {
@@ -5,89 +7,272 @@ pub(crate) fn run() {
     println!("day 10, output 2: {}", parse2(INPUT));
 }
 
+const ERROR_SCORES: &[(&str, usize)] = &[(")", 3), ("]", 57), ("}", 1197), (">", 25137)];
+const COMPLETION_SCORES: &[(&str, usize)] = &[(")", 1), ("]", 2), ("}", 3), (">", 4)];
+
 fn parse1(input: &str) -> usize {
+    let checker = SyntaxChecker::chunks();
     input
         .lines()
-        .map(|s| {
-            let mut stack: Vec<char> = vec![];
-            let mut this_line_score = 0;
-
-            for c in s.chars() {
-                match c {
-                    '<' | '{' | '(' | '[' => stack.push(c),
-                    '>' => {
-                        if stack.pop().unwrap() != '<' {
-                            this_line_score = 25137;
-                            break;
-                        }
-                    }
-                    '}' => {
-                        if stack.pop().unwrap() != '{' {
-                            this_line_score = 1197;
-                            break;
-                        }
-                    }
-                    ')' => {
-                        if stack.pop().unwrap() != '(' {
-                            this_line_score = 3;
-                            break;
-                        }
-                    }
-                    ']' => {
-                        if stack.pop().unwrap() != '[' {
-                            this_line_score = 57;
-                            break;
-                        }
-                    }
-                    _ => panic!("invalid character!"),
-                }
-            }
-
-            this_line_score
-        })
+        .map(|line| checker.check(line).error_score(ERROR_SCORES))
         .sum()
 }
 
 fn parse2(input: &str) -> usize {
-    let mut scores: Vec<usize> = vec![];
-    input.lines().for_each(|s| {
-        let mut stack = remainder(s);
-        let mut score = 0;
-        while !stack.is_empty() {
-            match stack.pop().unwrap() {
-                '(' => score = score * 5 + 1,
-                '[' => score = score * 5 + 2,
-                '{' => score = score * 5 + 3,
-                '<' => score = score * 5 + 4,
-                _ => {}
+    let checker = SyntaxChecker::chunks();
+    let mut scores: Vec<usize> = input
+        .lines()
+        .filter_map(|line| match checker.check(line) {
+            Outcome::Incomplete { completion } => {
+                Some(checker.completion_score(&completion, COMPLETION_SCORES, 5))
             }
-        }
+            _ => None,
+        })
+        .collect();
+
+    scores.sort_unstable();
+    scores[scores.len() / 2]
+}
 
-        if score > 0 {
-            scores.push(score);
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Valid,
+    /// `found` appeared at the 0-based character `column` where the closing
+    /// delimiter `expected` was needed, or where nothing was open to close.
+    Corrupted {
+        column: usize,
+        expected: Option<String>,
+        found: String,
+    },
+    /// The line ended early and needs `completion` appended to be valid.
+    Incomplete {
+        completion: String,
+    },
+}
+
+impl Outcome {
+    fn error_score(&self, table: &[(&str, usize)]) -> usize {
+        match self {
+            Outcome::Corrupted { found, .. } => lookup(table, found),
+            _ => 0,
         }
-    });
+    }
+}
+
+fn lookup(table: &[(&str, usize)], delimiter: &str) -> usize {
+    table
+        .iter()
+        .find(|(d, _)| *d == delimiter)
+        .map_or(0, |&(_, score)| score)
+}
+
+/// A line made valid with the fewest possible edits.
+#[derive(Debug, PartialEq)]
+struct Repair {
+    edits: usize,
+    line: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Token {
+    Open(usize),
+    Close(usize),
+}
 
-    scores.sort();
-    return scores[scores.len() / 2];
+/// Checks nesting of arbitrary, possibly multi-character, delimiter pairs.
+struct SyntaxChecker {
+    pairs: Vec<(String, String)>,
 }
 
-fn remainder(s: &str) -> Vec<char> {
-    let mut stack: Vec<char> = vec![];
+impl SyntaxChecker {
+    fn new(pairs: &[(&str, &str)]) -> Self {
+        Self {
+            pairs: pairs
+                .iter()
+                .map(|&(open, close)| (open.to_string(), close.to_string()))
+                .collect(),
+        }
+    }
+
+    fn chunks() -> Self {
+        Self::new(&[("(", ")"), ("[", "]"), ("{", "}"), ("<", ">")])
+    }
+
+    /// The longest delimiter at the start of `s` and its length in bytes.
+    /// Between delimiters of equal length, closing the innermost pair wins,
+    /// then opening a new one, so symmetric pairs like `|x|` work.
+    fn token(&self, s: &str, innermost: Option<usize>) -> Option<(Token, usize)> {
+        self.pairs
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (open, close))| [(Token::Open(i), open), (Token::Close(i), close)])
+            .filter(|(_, delimiter)| s.starts_with(delimiter.as_str()))
+            .max_by_key(|&(token, delimiter)| {
+                let rank = match token {
+                    Token::Close(i) if innermost == Some(i) => 2,
+                    Token::Open(_) => 1,
+                    Token::Close(_) => 0,
+                };
+                (delimiter.len(), rank)
+            })
+            .map(|(token, delimiter)| (token, delimiter.len()))
+    }
+
+    fn check(&self, line: &str) -> Outcome {
+        let mut stack: Vec<usize> = vec![];
+        let mut rest = line;
+        let mut column = 0;
+
+        while let Some(c) = rest.chars().next() {
+            let corrupted = |stack: &[usize], len: usize| Outcome::Corrupted {
+                column,
+                expected: stack.last().map(|&i| self.pairs[i].1.clone()),
+                found: rest[..len].to_string(),
+            };
+
+            let len = match self.token(rest, stack.last().copied()) {
+                Some((Token::Open(i), len)) => {
+                    stack.push(i);
+                    len
+                }
+                Some((Token::Close(i), len)) if stack.last() == Some(&i) => {
+                    stack.pop();
+                    len
+                }
+                Some((Token::Close(_), len)) => return corrupted(&stack, len),
+                None => return corrupted(&stack, c.len_utf8()),
+            };
 
-    let keep = s.chars().all(|c| match c {
-        '<' | '{' | '(' | '[' => {
-            stack.push(c);
-            true
+            column += rest[..len].chars().count();
+            rest = &rest[len..];
         }
-        '>' => stack.pop().unwrap() == '<',
-        '}' => stack.pop().unwrap() == '{',
-        ')' => stack.pop().unwrap() == '(',
-        ']' => stack.pop().unwrap() == '[',
-        _ => panic!("unexpected char"),
-    });
-
-    return if keep { stack } else { vec![] };
+
+        if stack.is_empty() {
+            Outcome::Valid
+        } else {
+            Outcome::Incomplete {
+                completion: stack
+                    .iter()
+                    .rev()
+                    .map(|&i| self.pairs[i].1.as_str())
+                    .collect(),
+            }
+        }
+    }
+
+    /// Split `line` into delimiters and single characters that aren't part
+    /// of any delimiter.
+    fn tokens<'a>(&self, line: &'a str) -> Vec<&'a str> {
+        let mut tokens = vec![];
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            let len = self.token(rest, None).map_or(c.len_utf8(), |(_, len)| len);
+            tokens.push(&rest[..len]);
+            rest = &rest[len..];
+        }
+
+        tokens
+    }
+
+    /// Make `line` valid with the fewest token insertions, deletions and
+    /// substitutions. This is an interval DP over the tokens: the first token
+    /// in a span is either dropped from the matching (costing one edit to
+    /// delete it or insert its partner), or paired with a later token and
+    /// both substituted as needed to form one of the delimiter pairs.
+    fn repair(&self, line: &str) -> Repair {
+        let tokens = self.tokens(line);
+        let n = tokens.len();
+
+        // Cheapest pair for tokens i and k, and the number of substitutions
+        let pair_cost = |i: usize, k: usize| -> (usize, usize) {
+            (0..self.pairs.len())
+                .map(|p| {
+                    let (open, close) = &self.pairs[p];
+                    let cost = (tokens[i] != open) as usize + (tokens[k] != close) as usize;
+                    (p, cost)
+                })
+                .min_by_key(|&(_, cost)| cost)
+                .unwrap()
+        };
+
+        // edits[i][j]: fewest edits to balance tokens[i..j]
+        let mut edits = vec![vec![0; n + 1]; n + 1];
+        for len in 1..=n {
+            for i in 0..=n - len {
+                let j = i + len;
+                let mut best = edits[i + 1][j] + 1;
+                for k in i + 1..j {
+                    let (_, cost) = pair_cost(i, k);
+                    best = best.min(cost + edits[i + 1][k] + edits[k + 1][j]);
+                }
+                edits[i][j] = best;
+            }
+        }
+
+        let mut repaired = String::new();
+        self.rebuild(&tokens, &edits, &pair_cost, 0, n, &mut repaired);
+
+        Repair {
+            edits: edits[0][n],
+            line: repaired,
+        }
+    }
+
+    fn rebuild(
+        &self,
+        tokens: &[&str],
+        edits: &[Vec<usize>],
+        pair_cost: &dyn Fn(usize, usize) -> (usize, usize),
+        i: usize,
+        j: usize,
+        out: &mut String,
+    ) {
+        if i == j {
+            return;
+        }
+
+        for k in i + 1..j {
+            let (p, cost) = pair_cost(i, k);
+            if cost + edits[i + 1][k] + edits[k + 1][j] == edits[i][j] {
+                out.push_str(&self.pairs[p].0);
+                self.rebuild(tokens, edits, pair_cost, i + 1, k, out);
+                out.push_str(&self.pairs[p].1);
+                self.rebuild(tokens, edits, pair_cost, k + 1, j, out);
+                return;
+            }
+        }
+
+        // Unmatched: close an opener at the end of the span, open a closer
+        // right before it, and delete anything else
+        let opener = self.pairs.iter().find(|(open, _)| open == tokens[i]);
+        let closer = self.pairs.iter().find(|(_, close)| close == tokens[i]);
+        match (opener, closer) {
+            (Some((open, close)), _) => {
+                out.push_str(open);
+                self.rebuild(tokens, edits, pair_cost, i + 1, j, out);
+                out.push_str(close);
+            }
+            (None, Some((open, close))) => {
+                out.push_str(open);
+                out.push_str(close);
+                self.rebuild(tokens, edits, pair_cost, i + 1, j, out);
+            }
+            (None, None) => self.rebuild(tokens, edits, pair_cost, i + 1, j, out),
+        }
+    }
+
+    /// Score a completion string, treating each closing delimiter in it as a
+    /// digit in base `multiplier`.
+    fn completion_score(
+        &self,
+        completion: &str,
+        table: &[(&str, usize)],
+        multiplier: usize,
+    ) -> usize {
+        self.tokens(completion)
+            .iter()
+            .fold(0, |score, token| score * multiplier + lookup(table, token))
+    }
 }
 
 #[cfg(test)]
@@ -114,4 +299,101 @@ mod test {
     fn second() {
         assert_eq!(parse2(INPUTS), 288957);
     }
+
+    #[test]
+    fn outcomes() {
+        let checker = SyntaxChecker::chunks();
+        assert_eq!(checker.check("[<>({}){}[([])<>]]"), Outcome::Valid);
+        assert_eq!(
+            checker.check("{([(<{}[<>[]}>{[]{[(<()>"),
+            Outcome::Corrupted {
+                column: 12,
+                expected: Some("]".to_string()),
+                found: "}".to_string(),
+            }
+        );
+        assert_eq!(
+            checker.check("())"),
+            Outcome::Corrupted {
+                column: 2,
+                expected: None,
+                found: ")".to_string(),
+            }
+        );
+        assert_eq!(
+            checker.check("(a)"),
+            Outcome::Corrupted {
+                column: 1,
+                expected: Some(")".to_string()),
+                found: "a".to_string(),
+            }
+        );
+        assert_eq!(
+            checker.check("[({(<(())[]>[[{[]{<()<>>"),
+            Outcome::Incomplete {
+                completion: "}}]])})]".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn multi_character_delimiters() {
+        let checker = SyntaxChecker::new(&[("(", ")"), ("(*", "*)"), ("begin", "end"), ("|", "|")]);
+        assert_eq!(
+            checker.check("begin(*(|x|)*)end"),
+            Outcome::Corrupted {
+                column: 9,
+                expected: Some("|".to_string()),
+                found: "x".to_string(),
+            }
+        );
+        assert_eq!(checker.check("begin(*(||)*)end"), Outcome::Valid);
+        assert_eq!(
+            checker.check("begin(*|"),
+            Outcome::Incomplete {
+                completion: "|*)end".to_string()
+            }
+        );
+        assert_eq!(
+            checker.check("begin(*)"),
+            Outcome::Corrupted {
+                column: 7,
+                expected: Some("*)".to_string()),
+                found: ")".to_string(),
+            }
+        );
+
+        let table = [("|", 1), ("*)", 2), ("end", 3)];
+        assert_eq!(
+            checker.completion_score("|*)end", &table, 4),
+            16 + 2 * 4 + 3
+        );
+    }
+
+    #[test]
+    fn repair() {
+        let checker = SyntaxChecker::chunks();
+        let repair = |line: &str| {
+            let repair = checker.repair(line);
+            (repair.edits, repair.line)
+        };
+
+        assert_eq!(
+            repair("[<>({}){}[([])<>]]"),
+            (0, "[<>({}){}[([])<>]]".to_string())
+        );
+        assert_eq!(repair("(]"), (1, "()".to_string()));
+        assert_eq!(repair("())"), (1, "()()".to_string()));
+        assert_eq!(repair("[("), (1, "[]".to_string()));
+        assert_eq!(repair("(a)"), (1, "()".to_string()));
+        assert_eq!(repair(")("), (2, "()".to_string()));
+
+        for line in INPUTS.lines() {
+            let repaired = checker.repair(line);
+            assert!(repaired.edits > 0);
+            assert_eq!(checker.check(&repaired.line), Outcome::Valid);
+        }
+
+        assert_eq!(checker.repair("{([(<{}[<>[]}>{[]{[(<()>").edits, 5);
+    }
 }
@@ -1,4 +1,6 @@
-use std::collections::VecDeque;
+#![allow(dead_code)]
+
+use std::collections::{HashSet, VecDeque};
 use std::str::FromStr;
 
 const INPUT: &'static str = include_str!("../inputs/day11.txt");
@@ -9,114 +11,162 @@ pub(crate) fn run() {
 }
 
 fn parse1(input: &str) -> usize {
-    let mut flash_count = 0;
+    let mut og: OctoGrid = input.parse().unwrap();
+    og.flash_series(100).iter().sum()
+}
 
+fn parse2(input: &str) -> usize {
     let mut og: OctoGrid = input.parse().unwrap();
+    og.sync_step().unwrap()
+}
 
-    for _ in 1..=100 {
-        let mut queue = VecDeque::new();
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Neighbourhood {
+    /// The four orthogonal neighbours.
+    VonNeumann,
+    /// The eight orthogonal and diagonal neighbours.
+    Moore,
+}
 
-        // Increase all energy levels
-        for row in 0..og.n_rows {
-            for col in 0..og.n_cols {
-                if og.increment(row, col) {
-                    queue.push_back((row, col))
-                }
-            }
-        }
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Edges {
+    Bounded,
+    Wrapping,
+}
 
-        // Follow all downstream effects
-        while !queue.is_empty() {
-            let (row, col) = queue.pop_front().unwrap();
-            let min_row = 0.max(row as i32 - 1) as usize;
-            let max_row = (row + 1).min(og.n_rows - 1);
-            let min_col = 0.max(col as i32 - 1) as usize;
-            let max_col = (col + 1).min(og.n_cols - 1);
-            for r in min_row..=max_row {
-                for c in min_col..=max_col {
-                    if !og.flashed[r][c] && og.increment(r, c) {
-                        queue.push_back((r, c));
-                    }
-                }
-            }
-        }
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Rules {
+    /// Octopuses flash once their energy goes above this.
+    threshold: usize,
+    /// Energy every octopus gains at the start of a step.
+    increment: usize,
+    neighbourhood: Neighbourhood,
+    edges: Edges,
+}
 
-        flash_count += og.count_flashes();
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            threshold: 9,
+            increment: 1,
+            neighbourhood: Neighbourhood::Moore,
+            edges: Edges::Bounded,
+        }
     }
+}
 
-    flash_count
+struct OctoGrid {
+    grid: Vec<Vec<usize>>,
+    n_rows: usize,
+    n_cols: usize,
+    rules: Rules,
 }
 
-fn parse2(input: &str) -> usize {
-    let mut og: OctoGrid = input.parse().unwrap();
+impl OctoGrid {
+    fn with_rules(self, rules: Rules) -> Self {
+        Self { rules, ..self }
+    }
+
+    fn neighbours(&self, row: usize, col: usize) -> Vec<(usize, usize)> {
+        let offsets: &[(isize, isize)] = match self.rules.neighbourhood {
+            Neighbourhood::VonNeumann => &[(-1, 0), (0, -1), (0, 1), (1, 0)],
+            Neighbourhood::Moore => &[
+                (-1, -1),
+                (-1, 0),
+                (-1, 1),
+                (0, -1),
+                (0, 1),
+                (1, -1),
+                (1, 0),
+                (1, 1),
+            ],
+        };
 
-    for step in 1.. {
+        let (n_rows, n_cols) = (self.n_rows as isize, self.n_cols as isize);
+        let mut neighbours: Vec<(usize, usize)> = offsets
+            .iter()
+            .filter_map(|(dr, dc)| {
+                let (r, c) = (row as isize + dr, col as isize + dc);
+                match self.rules.edges {
+                    Edges::Bounded if r < 0 || c < 0 || r >= n_rows || c >= n_cols => None,
+                    Edges::Bounded => Some((r as usize, c as usize)),
+                    Edges::Wrapping => {
+                        Some((r.rem_euclid(n_rows) as usize, c.rem_euclid(n_cols) as usize))
+                    }
+                }
+            })
+            .filter(|&cell| cell != (row, col))
+            .collect();
+
+        // On grids narrower than 3 cells, wrapped offsets can reach the same
+        // neighbour from both sides
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        neighbours
+    }
+
+    /// Advance one step and return how many octopuses flashed.
+    fn step(&mut self) -> usize {
+        let mut flashed = vec![vec![false; self.n_cols]; self.n_rows];
         let mut queue = VecDeque::new();
 
         // Increase all energy levels
-        for row in 0..og.n_rows {
-            for col in 0..og.n_cols {
-                if og.increment(row, col) {
-                    queue.push_back((row, col))
+        for (row, cells) in self.grid.iter_mut().enumerate() {
+            for (col, energy) in cells.iter_mut().enumerate() {
+                *energy += self.rules.increment;
+                if *energy > self.rules.threshold {
+                    flashed[row][col] = true;
+                    queue.push_back((row, col));
                 }
             }
         }
 
         // Follow all downstream effects
-        while !queue.is_empty() {
-            let (row, col) = queue.pop_front().unwrap();
-            let min_row = 0.max(row as i32 - 1) as usize;
-            let max_row = (row + 1).min(og.n_rows - 1);
-            let min_col = 0.max(col as i32 - 1) as usize;
-            let max_col = (col + 1).min(og.n_cols - 1);
-            for r in min_row..=max_row {
-                for c in min_col..=max_col {
-                    if !og.flashed[r][c] && og.increment(r, c) {
-                        queue.push_back((r, c));
-                    }
+        while let Some((row, col)) = queue.pop_front() {
+            for (r, c) in self.neighbours(row, col) {
+                if flashed[r][c] {
+                    continue;
+                }
+
+                self.grid[r][c] += 1;
+                if self.grid[r][c] > self.rules.threshold {
+                    flashed[r][c] = true;
+                    queue.push_back((r, c));
                 }
             }
         }
 
-        if og.count_flashes() == 100 {
-            return step;
+        let mut n_flashed = 0;
+        for (cells, flashed) in self.grid.iter_mut().zip(&flashed) {
+            for (energy, _) in cells.iter_mut().zip(flashed).filter(|(_, &f)| f) {
+                *energy = 0;
+                n_flashed += 1;
+            }
         }
-    }
-
-    unreachable!();
-}
 
-struct OctoGrid {
-    grid: Vec<Vec<usize>>,
-    n_rows: usize,
-    n_cols: usize,
-    flashed: Vec<Vec<bool>>,
-}
+        n_flashed
+    }
 
-impl OctoGrid {
-    fn count_flashes(&mut self) -> usize {
-        let n_flashed = self
-            .flashed
-            .iter()
-            .map(|row| row.iter().filter(|em| **em).count())
-            .sum();
+    /// Flash counts for each of the next `n_steps` steps.
+    fn flash_series(&mut self, n_steps: usize) -> Vec<usize> {
+        (0..n_steps).map(|_| self.step()).collect()
+    }
 
-        self.grid.iter_mut().for_each(|row| {
-            row.iter_mut().for_each(|val| {
-                if *val > 9 {
-                    *val = 0;
-                }
-            })
-        });
-        self.flashed = vec![vec![false; self.n_cols]; self.n_rows];
+    /// The first step on which every octopus flashes, or `None` if the grid
+    /// falls into a cycle that never synchronises.
+    fn sync_step(&mut self) -> Option<usize> {
+        let mut seen = HashSet::new();
+        for step in 1.. {
+            if !seen.insert(self.grid.clone()) {
+                return None;
+            }
 
-        n_flashed
-    }
+            if self.step() == self.n_rows * self.n_cols {
+                return Some(step);
+            }
+        }
 
-    fn increment(&mut self, row: usize, col: usize) -> bool {
-        self.grid[row][col] += 1;
-        self.flashed[row][col] = self.grid[row][col] > 9;
-        self.flashed[row][col]
+        unreachable!();
     }
 }
 
@@ -136,13 +186,11 @@ impl FromStr for OctoGrid {
         let n_rows = grid.len();
         let n_cols = grid[0].len();
 
-        let flashed = vec![vec![false; n_cols]; n_rows];
-
         Ok(OctoGrid {
             grid,
             n_rows,
             n_cols,
-            flashed,
+            rules: Rules::default(),
         })
     }
 }
@@ -171,4 +219,63 @@ mod test {
     fn second() {
         assert_eq!(parse2(INPUTS), 195);
     }
+
+    #[test]
+    fn flash_series() {
+        let mut og: OctoGrid = INPUTS.parse().unwrap();
+        assert_eq!(og.flash_series(3), vec![0, 35, 45]);
+    }
+
+    #[test]
+    fn any_grid_size() {
+        let mut og: OctoGrid = "11111
+19991
+19191
+19991
+11111"
+            .parse()
+            .unwrap();
+        assert_eq!(og.flash_series(2), vec![9, 0]);
+
+        let mut og: OctoGrid = "9".parse().unwrap();
+        assert_eq!(og.sync_step(), Some(1));
+
+        let mut og: OctoGrid = "0000\n0000".parse().unwrap();
+        assert_eq!(og.sync_step(), Some(10));
+    }
+
+    #[test]
+    fn rules() {
+        let rules = Rules {
+            threshold: 4,
+            increment: 2,
+            neighbourhood: Neighbourhood::VonNeumann,
+            edges: Edges::Wrapping,
+        };
+
+        // Wrapping spreads the corner flash to the opposite corners
+        let mut og = "400\n000\n000"
+            .parse::<OctoGrid>()
+            .unwrap()
+            .with_rules(rules);
+        assert_eq!(og.step(), 1);
+        assert_eq!(og.grid, vec![vec![0, 3, 3], vec![3, 2, 2], vec![3, 2, 2]]);
+
+        // Each neighbour gets one flash's worth, however many ways round
+        let rules = Rules {
+            edges: Edges::Wrapping,
+            ..Rules::default()
+        };
+        let mut og = "90\n00".parse::<OctoGrid>().unwrap().with_rules(rules);
+        assert_eq!(og.step(), 1);
+        assert_eq!(og.grid, vec![vec![0, 2], vec![2, 2]]);
+
+        // A checkerboard never lines up when flashes only reach 2 neighbours
+        let rules = Rules {
+            neighbourhood: Neighbourhood::VonNeumann,
+            ..Rules::default()
+        };
+        let mut og = "05\n50".parse::<OctoGrid>().unwrap().with_rules(rules);
+        assert_eq!(og.sync_step(), None);
+    }
 }
@@ -1,4 +1,8 @@
-use std::collections::{HashMap, HashSet};
+#![allow(dead_code)]
+
+use num_bigint::BigUint;
+use std::collections::HashMap;
+use std::fmt;
 use std::str::FromStr;
 
 const INPUT: &'static str = include_str!("../inputs/day12.txt");
@@ -8,7 +12,15 @@ pub(crate) fn run() {
     println!("day 12, output 2: {}", parse2(INPUT));
 }
 
-fn parse1(input: &str) -> usize {
+fn parse1(input: &str) -> BigUint {
+    CaveCounter::new(&parse_graph(input)).unwrap().count(false)
+}
+
+fn parse2(input: &str) -> BigUint {
+    CaveCounter::new(&parse_graph(input)).unwrap().count(true)
+}
+
+fn parse_graph(input: &str) -> Graph {
     let mut graph: Graph = Graph::new();
     for line in input.lines() {
         let (a, b) = line.split_once('-').unwrap();
@@ -18,81 +30,128 @@ fn parse1(input: &str) -> usize {
         graph.insert(a, b);
     }
 
-    let visited: HashSet<Cave> = HashSet::new();
-    count_paths(&graph, Cave::Start, visited)
+    graph
 }
 
-fn parse2(input: &str) -> usize {
-    let mut graph: Graph = Graph::new();
-    for line in input.lines() {
-        let (a, b) = line.split_once('-').unwrap();
-        let a = a.parse().unwrap();
-        let b = b.parse().unwrap();
+/// How often a path may return to small caves it has already visited.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Revisits {
+    Never,
+    /// A single small cave may be visited twice.
+    SingleTwice,
+    /// Every small cave may be visited up to this many times.
+    EachUpTo(usize),
+    /// Small caves may be revisited this many times in total.
+    Total(usize),
+}
 
-        graph.insert(a, b);
+impl Revisits {
+    /// Whether a small cave already visited `visits` times can be entered
+    /// again, given the path has made `revisits` revisits so far.
+    fn allows(&self, visits: usize, revisits: usize) -> bool {
+        match *self {
+            Revisits::Never => visits == 0,
+            Revisits::SingleTwice => visits == 0 || (visits == 1 && revisits == 0),
+            Revisits::EachUpTo(k) => visits < k,
+            Revisits::Total(m) => visits == 0 || revisits < m,
+        }
     }
+}
 
-    let visited: HashSet<Cave> = HashSet::new();
-    count_paths2(&graph, Cave::Start, visited, false)
+/// Every path from start to end allowed by the revisit policy, found by a
+/// depth first search that keeps its state between calls to `next`.
+struct Paths<'a> {
+    graph: &'a Graph,
+    policy: Revisits,
+    path: Vec<Cave>,
+    /// Index of the next neighbour to try from each cave on the path.
+    next: Vec<usize>,
+    visits: HashMap<Cave, usize>,
+    revisits: usize,
 }
 
-fn count_paths(graph: &Graph, current: Cave, mut visited: HashSet<Cave>) -> usize {
-    if current == Cave::End {
-        return 1;
-    } else if current.is_small() && visited.contains(&current) {
-        return 0;
+impl<'a> Paths<'a> {
+    fn new(graph: &'a Graph, policy: Revisits) -> Self {
+        Self {
+            graph,
+            policy,
+            path: vec![Cave::Start],
+            next: vec![0],
+            visits: HashMap::new(),
+            revisits: 0,
+        }
     }
 
-    if current.is_small() {
-        visited.insert(current.clone());
+    fn backtrack(&mut self) {
+        self.next.pop();
+        if let Some(cave) = self.path.pop().filter(Cave::is_small) {
+            let visits = self.visits.get_mut(&cave).unwrap();
+            if *visits > 1 {
+                self.revisits -= 1;
+            }
+            *visits -= 1;
+        }
     }
-
-    graph
-        .get(&current)
-        .unwrap()
-        .into_iter()
-        .map(|next| count_paths(graph, next.clone(), visited.clone()))
-        .sum()
 }
 
-fn count_paths2(
-    graph: &Graph,
-    current: Cave,
-    mut visited: HashSet<Cave>,
-    mut used_second: bool,
-) -> usize {
-    if current == Cave::End {
-        return 1;
-    } else if current.is_small() && visited.contains(&current) && used_second {
-        return 0;
-    }
+impl<'a> Iterator for Paths<'a> {
+    type Item = Vec<Cave>;
 
-    used_second |= current.is_small() && visited.contains(&current);
+    fn next(&mut self) -> Option<Self::Item> {
+        let graph = self.graph;
+        while let Some(current) = self.path.last() {
+            let neighbours = graph.get(current).map_or(&[][..], |n| n.as_slice());
+            let next = self.next.last_mut().unwrap();
+            if *next == neighbours.len() {
+                self.backtrack();
+                continue;
+            }
 
-    if current.is_small() {
-        visited.insert(current.clone());
-    }
+            let cave = &neighbours[*next];
+            *next += 1;
 
-    graph
-        .get(&current)
-        .unwrap()
-        .into_iter()
-        .map(|next| count_paths2(graph, next.clone(), visited.clone(), used_second))
-        .sum()
+            if *cave == Cave::End {
+                let mut path = self.path.clone();
+                path.push(Cave::End);
+                return Some(path);
+            }
+
+            if cave.is_small() {
+                let visits = self.visits.get(cave).copied().unwrap_or(0);
+                if !self.policy.allows(visits, self.revisits) {
+                    continue;
+                }
+
+                if visits > 0 {
+                    self.revisits += 1;
+                }
+                *self.visits.entry(cave.clone()).or_default() += 1;
+            }
+
+            self.path.push(cave.clone());
+            self.next.push(0);
+        }
+
+        None
+    }
 }
 
 struct Graph {
     map: HashMap<Cave, Vec<Cave>>,
+    edges: Vec<(Cave, Cave)>,
 }
 
 impl Graph {
     fn new() -> Self {
         Self {
             map: HashMap::new(),
+            edges: Vec::new(),
         }
     }
 
     fn insert(&mut self, from: Cave, to: Cave) {
+        self.edges.push((from.clone(), to.clone()));
+
         let edges = self.map.entry(from.clone()).or_default();
         if !to.is_start() {
             edges.push(to.clone());
@@ -107,9 +166,172 @@ impl Graph {
     fn get(&self, key: &Cave) -> Option<&Vec<Cave>> {
         self.map.get(key)
     }
+
+    /// Render the cave system as a Graphviz graph, with small caves as
+    /// circles and large caves as boxes. The caves and passages along
+    /// `highlight` are drawn in red.
+    fn to_dot(&self, highlight: Option<&[Cave]>) -> String {
+        let path = highlight.unwrap_or(&[]);
+        let on_path = |a: &Cave, b: &Cave| {
+            path.windows(2)
+                .any(|w| (&w[0], &w[1]) == (a, b) || (&w[0], &w[1]) == (b, a))
+        };
+
+        let mut caves: Vec<&Cave> = vec![];
+        for (a, b) in &self.edges {
+            for cave in [a, b] {
+                if !caves.contains(&cave) {
+                    caves.push(cave);
+                }
+            }
+        }
+
+        let mut dot = String::from("graph caves {\n");
+        for cave in caves {
+            let shape = match cave {
+                Cave::Large(_) => "box",
+                Cave::Start | Cave::End => "doublecircle",
+                Cave::Small(_) => "circle",
+            };
+            let colour = if path.contains(cave) {
+                ", color=red"
+            } else {
+                ""
+            };
+            dot += &format!("    \"{}\" [shape={}{}];\n", cave, shape, colour);
+        }
+
+        for (a, b) in &self.edges {
+            let colour = if on_path(a, b) {
+                " [color=red, penwidth=2]"
+            } else {
+                ""
+            };
+            dot += &format!("    \"{}\" -- \"{}\"{};\n", a, b, colour);
+        }
+        dot += "}\n";
+
+        dot
+    }
+}
+
+/// Counts paths without enumerating them. Small caves are interned to bits
+/// of a `u64` visited mask, and large caves are folded into weighted edges
+/// between the small caves either side of them: `weights[a][b]` is the
+/// number of ways to step from `a` to `b`, directly or through one large
+/// cave. Start and end take the two ids after the small caves.
+struct CaveCounter {
+    weights: Vec<Vec<u64>>,
+    start: usize,
+    end: usize,
+}
+
+impl CaveCounter {
+    /// Fails if two large caves are joined, as paths could then bounce
+    /// between them forever, or if there are more than 64 small caves.
+    fn new(graph: &Graph) -> Option<Self> {
+        let mut ids: HashMap<&Cave, usize> = HashMap::new();
+        let mut large: HashMap<&Cave, Vec<&Cave>> = HashMap::new();
+        for (a, b) in &graph.edges {
+            match (a, b) {
+                (Cave::Large(_), Cave::Large(_)) => return None,
+                (Cave::Large(_), other) => large.entry(a).or_default().push(other),
+                (other, Cave::Large(_)) => large.entry(b).or_default().push(other),
+                _ => {}
+            }
+
+            for cave in [a, b] {
+                if cave.is_small() && !ids.contains_key(cave) {
+                    ids.insert(cave, ids.len());
+                }
+            }
+        }
+
+        let n_small = ids.len();
+        if n_small > 64 {
+            return None;
+        }
+
+        let (start, end) = (n_small, n_small + 1);
+        ids.insert(&Cave::Start, start);
+        ids.insert(&Cave::End, end);
+
+        let mut weights = vec![vec![0; n_small + 2]; n_small + 2];
+        let mut join = |a: &Cave, b: &Cave| {
+            if !b.is_start() && !a.is_end() {
+                weights[ids[a]][ids[b]] += 1;
+            }
+        };
+
+        for (a, b) in &graph.edges {
+            if !matches!(a, Cave::Large(_)) && !matches!(b, Cave::Large(_)) {
+                join(a, b);
+                join(b, a);
+            }
+        }
+
+        for neighbours in large.values() {
+            for a in neighbours {
+                for b in neighbours {
+                    join(a, b);
+                }
+            }
+        }
+
+        Some(Self {
+            weights,
+            start,
+            end,
+        })
+    }
+
+    /// Number of paths from start to end, optionally allowing a single small
+    /// cave to be visited twice.
+    fn count(&self, revisit: bool) -> BigUint {
+        let mut memo = HashMap::new();
+        self.count_from(self.start, 0, !revisit, &mut memo)
+    }
+
+    fn count_from(
+        &self,
+        cave: usize,
+        visited: u64,
+        revisited: bool,
+        memo: &mut HashMap<(usize, u64, bool), BigUint>,
+    ) -> BigUint {
+        if cave == self.end {
+            return BigUint::from(1u32);
+        }
+
+        if let Some(count) = memo.get(&(cave, visited, revisited)) {
+            return count.clone();
+        }
+
+        let mut count = BigUint::default();
+        for (next, &ways) in self.weights[cave].iter().enumerate() {
+            if ways == 0 {
+                continue;
+            }
+
+            if next == self.end {
+                count += ways;
+                continue;
+            }
+
+            let bit = 1 << next;
+            if visited & bit == 0 {
+                count += ways * self.count_from(next, visited | bit, revisited, memo);
+            } else if !revisited {
+                count += ways * self.count_from(next, visited, true, memo);
+            }
+        }
+
+        memo.insert((cave, visited, revisited), count.clone());
+        count
+    }
 }
 
-#[derive(Hash, PartialEq, Eq, Clone)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
 enum Cave {
     Start,
     End,
@@ -122,11 +344,25 @@ impl Cave {
         matches!(self, Self::Start)
     }
 
+    fn is_end(&self) -> bool {
+        matches!(self, Self::End)
+    }
+
     fn is_small(&self) -> bool {
         matches!(self, Self::Small(_))
     }
 }
 
+impl fmt::Display for Cave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Start => write!(f, "start"),
+            Self::End => write!(f, "end"),
+            Self::Small(name) | Self::Large(name) => write!(f, "{}", name),
+        }
+    }
+}
+
 impl FromStr for Cave {
     type Err = std::io::Error;
 
@@ -168,11 +404,106 @@ start-RW";
 
     #[test]
     fn first() {
-        assert_eq!(parse1(INPUTS), 226);
+        assert_eq!(parse1(INPUTS), BigUint::from(226u32));
     }
 
     #[test]
     fn second() {
-        assert_eq!(parse2(INPUTS), 3509);
+        assert_eq!(parse2(INPUTS), BigUint::from(3509u32));
+    }
+
+    const SMALL: &'static str = "start-A
+start-b
+A-c
+A-b
+b-d
+A-end
+b-end";
+
+    fn path(caves: &str) -> Vec<Cave> {
+        caves.split(',').map(|c| c.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn paths() {
+        let graph = parse_graph(SMALL);
+        let paths: Vec<Vec<Cave>> = Paths::new(&graph, Revisits::Never).collect();
+        assert_eq!(paths.len(), 10);
+        assert!(paths.contains(&path("start,A,c,A,b,A,end")));
+        assert!(paths.contains(&path("start,b,end")));
+
+        let count = |policy| Paths::new(&graph, policy).count();
+        assert_eq!(count(Revisits::SingleTwice), 36);
+        assert_eq!(count(Revisits::Total(0)), 10);
+        assert_eq!(count(Revisits::Total(1)), 36);
+        assert_eq!(count(Revisits::EachUpTo(1)), 10);
+
+        assert_eq!(count(Revisits::Total(2)), 101);
+        assert_eq!(count(Revisits::EachUpTo(2)), 54);
+        assert_eq!(count(Revisits::EachUpTo(3)), 278);
+    }
+
+    #[test]
+    fn dot() {
+        let graph = parse_graph("start-A\nA-b\nA-end");
+        let dot = graph.to_dot(Some(&path("start,A,end")));
+        assert_eq!(
+            dot,
+            r#"graph caves {
+    "start" [shape=doublecircle, color=red];
+    "A" [shape=box, color=red];
+    "b" [shape=circle];
+    "end" [shape=doublecircle, color=red];
+    "start" -- "A" [color=red, penwidth=2];
+    "A" -- "b";
+    "A" -- "end" [color=red, penwidth=2];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn counting_matches_enumeration() {
+        for input in [SMALL, INPUTS] {
+            let graph = parse_graph(input);
+            let counter = CaveCounter::new(&graph).unwrap();
+            for (revisit, policy) in [(false, Revisits::Never), (true, Revisits::SingleTwice)] {
+                let paths = BigUint::from(Paths::new(&graph, policy).count());
+                assert_eq!(counter.count(revisit), paths);
+            }
+        }
+
+        assert!(CaveCounter::new(&parse_graph("start-A\nA-B\nB-end")).is_none());
+    }
+
+    #[test]
+    fn many_small_caves() {
+        // A chain of 64 small caves, each joined to the next through two or
+        // four large caves, so every one of the 65 hops has that many routes
+        let name = |i: usize| {
+            format!(
+                "{}{}",
+                (b'a' + (i / 26) as u8) as char,
+                (b'a' + (i % 26) as u8) as char
+            )
+        };
+        let caves: Vec<String> = std::iter::once("start".to_string())
+            .chain((0..64).map(name))
+            .chain(std::iter::once("end".to_string()))
+            .collect();
+
+        for (large_caves, expected) in [(&["X", "Y"][..], 65), (&["W", "X", "Y", "Z"][..], 130)] {
+            let mut input = vec![];
+            for (i, pair) in caves.windows(2).enumerate() {
+                for large in large_caves {
+                    let large = format!("{}{}", large, name(i).to_uppercase());
+                    input.push(format!("{}-{}", pair[0], large));
+                    input.push(format!("{}-{}", large, pair[1]));
+                }
+            }
+
+            let counter = CaveCounter::new(&parse_graph(&input.join("\n"))).unwrap();
+            assert_eq!(counter.count(false), BigUint::from(1u32) << expected);
+        }
     }
 }
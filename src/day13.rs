@@ -1,8 +1,22 @@
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+
+use crate::ocr;
+
 const INPUT: &'static str = include_str!("../inputs/day13.txt");
 
 pub(crate) fn run() {
     println!("day 13, output 1: {}", parse1(INPUT));
-    println!("day 13, output 2: {}", parse2(INPUT));
+    match parse2(INPUT) {
+        Ok(text) => println!("day 13, output 2: {}", text),
+        Err(unknown) => {
+            println!("day 13, output 2: unreadable");
+            for glyph in unknown {
+                print!("{}", glyph);
+            }
+        }
+    }
 }
 
 fn parse1(input: &str) -> usize {
@@ -14,7 +28,7 @@ fn parse1(input: &str) -> usize {
     paper.count_dots()
 }
 
-fn parse2(input: &str) -> usize {
+fn parse2(input: &str) -> Result<String, Vec<ocr::UnknownGlyph>> {
     let (paper, folds) = input.split_once("\n\n").unwrap();
     let mut paper = Paper::from_str(paper);
     let folds: Vec<Fold> = folds.lines().map(|line| Fold::from_str(line)).collect();
@@ -22,96 +36,85 @@ fn parse2(input: &str) -> usize {
     for fold in folds {
         paper.fold(&fold);
     }
-    for row in paper.dots.iter() {
-        for col in row.iter() {
-            if *col {
-                print!("##");
-            } else {
-                print!("..");
-            }
-        }
-        println!("");
-    }
 
-    0
+    ocr::recognise(&paper.grid())
 }
 
+/// Dots on transparent paper. Folds can overhang past the edge of the paper,
+/// so coordinates may go negative.
+#[derive(Debug, PartialEq)]
 struct Paper {
-    dots: Vec<Vec<bool>>,
+    dots: HashSet<(i64, i64)>,
 }
 
 impl Paper {
     fn from_str(s: &str) -> Self {
-        let mut width = 0;
-        let mut height = 0;
-        let mut points: Vec<(usize, usize)> = Vec::new();
-
-        for line in s.lines() {
-            let (x, y) = line.split_once(',').unwrap();
-            let x = x.parse().unwrap();
-            let y = y.parse().unwrap();
-            points.push((x, y));
-
-            width = width.max(x + 1);
-            height = height.max(y + 1);
-        }
-
-        let mut dots = vec![vec![false; width]; height];
-
-        for (x, y) in points {
-            dots[y][x] = true
-        }
+        let dots = s
+            .lines()
+            .map(|line| {
+                let (x, y) = line.split_once(',').unwrap();
+                (x.parse().unwrap(), y.parse().unwrap())
+            })
+            .collect();
 
         Paper { dots }
     }
 
+    /// Fold the far side of the line over onto the near side. Dots on the
+    /// fold line itself stay where they are.
     fn fold(&mut self, fold: &Fold) {
-        match fold {
-            Fold::X(pos) => {
-                self.dots = self
-                    .dots
-                    .iter()
-                    .map(|row| {
-                        let mut new_row = vec![];
-                        for idx in 0..*pos {
-                            let mirror_val = row.get(2 * pos - idx).unwrap_or(&false);
-                            new_row.push(*row.get(idx).unwrap() || *mirror_val);
-                        }
-                        new_row
-                    })
-                    .collect();
-            }
-            Fold::Y(pos) => {
-                for row in 0..*pos {
-                    let mirror_row = 2 * pos - row;
-                    if mirror_row < self.dots.len() {
-                        for col in 0..self.dots[0].len() {
-                            self.dots[row][col] |= self.dots[mirror_row][col];
-                        }
-                    }
-                }
-
-                self.dots = self.dots[0..*pos].to_vec();
-            }
-        }
+        self.dots = self.dots.iter().map(|&dot| fold.apply(dot)).collect();
     }
 
-    fn count_dots(&self) -> usize {
-        self.dots
+    /// Every position a dot could have occupied before `fold`: each dot
+    /// either stayed put, came from its mirror image, or both.
+    fn unfold(&self, fold: &Fold) -> Paper {
+        let dots = self
+            .dots
             .iter()
-            .map(|row| row.iter().filter(|is_dot| **is_dot).count())
-            .sum()
+            .flat_map(|&dot| [dot, fold.mirror(dot)])
+            .collect();
+
+        Paper { dots }
+    }
+
+    fn count_dots(&self) -> usize {
+        self.dots.len()
+    }
+
+    /// Dense `[row][col]` grid of the dots, starting from the origin or from
+    /// the furthest overhang if that's further up or left.
+    fn grid(&self) -> Vec<Vec<bool>> {
+        let min_x = self.dots.iter().map(|&(x, _)| x).min().unwrap_or(0).min(0);
+        let min_y = self.dots.iter().map(|&(_, y)| y).min().unwrap_or(0).min(0);
+        let max_x = self.dots.iter().map(|&(x, _)| x).max().unwrap_or(-1);
+        let max_y = self.dots.iter().map(|&(_, y)| y).max().unwrap_or(-1);
+
+        let mut grid =
+            vec![vec![false; (max_x - min_x + 1) as usize]; (max_y - min_y + 1) as usize];
+        for &(x, y) in &self.dots {
+            grid[(y - min_y) as usize][(x - min_x) as usize] = true;
+        }
+
+        grid
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 enum Fold {
-    X(usize),
-    Y(usize),
+    /// Fold the right of `x = n` over to the left.
+    X(i64),
+    /// Fold the bottom of `y = n` up.
+    Y(i64),
+    /// Fold along `x = y`, bringing the bottom left over to the top right.
+    Diagonal,
 }
 
 impl Fold {
     fn from_str(s: &str) -> Self {
-        if let Some((_, coord)) = s.split_once("x=") {
+        if s.ends_with("x=y") {
+            Self::Diagonal
+        } else if let Some((_, coord)) = s.split_once("x=") {
             Self::X(coord.parse().unwrap())
         } else if let Some((_, coord)) = s.split_once("y=") {
             Self::Y(coord.parse().unwrap())
@@ -119,6 +122,28 @@ impl Fold {
             unreachable!();
         }
     }
+
+    fn mirror(&self, (x, y): (i64, i64)) -> (i64, i64) {
+        match *self {
+            Self::X(pos) => (2 * pos - x, y),
+            Self::Y(pos) => (x, 2 * pos - y),
+            Self::Diagonal => (y, x),
+        }
+    }
+
+    fn apply(&self, (x, y): (i64, i64)) -> (i64, i64) {
+        let folded = match *self {
+            Self::X(pos) => x > pos,
+            Self::Y(pos) => y > pos,
+            Self::Diagonal => y > x,
+        };
+
+        if folded {
+            self.mirror((x, y))
+        } else {
+            (x, y)
+        }
+    }
 }
 
 #[cfg(test)]
@@ -153,5 +178,53 @@ fold along x=5";
     }
 
     #[test]
-    fn second() {}
+    fn second() {
+        assert_eq!(parse2(INPUT), Ok("RGZLBHFP".to_string()));
+
+        // The example folds into a square, which isn't a letter
+        let unknown = parse2(INPUTS).unwrap_err();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(
+            unknown[0].bitmap,
+            vec!["#####", "#...#", "#...#", "#...#", "#####", "....."]
+        );
+    }
+
+    fn dots(dots: &[(i64, i64)]) -> Paper {
+        Paper {
+            dots: dots.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn uneven_folds() {
+        // The bottom is longer than the top, so it overhangs past the edge
+        let mut paper = dots(&[(0, 0), (0, 3), (1, 9), (2000000, 5000000)]);
+        paper.fold(&Fold::Y(2));
+        assert_eq!(paper, dots(&[(0, 0), (0, 1), (1, -5), (2000000, -4999996)]));
+
+        paper.fold(&Fold::X(1000000));
+        assert_eq!(paper, dots(&[(0, 0), (0, 1), (1, -5), (0, -4999996)]));
+    }
+
+    #[test]
+    fn diagonal_fold() {
+        let mut paper = Paper::from_str("0,3\n3,0\n1,4\n2,2");
+        paper.fold(&Fold::from_str("fold along x=y"));
+        assert_eq!(paper, dots(&[(3, 0), (4, 1), (2, 2)]));
+    }
+
+    #[test]
+    fn unfold() {
+        let original = dots(&[(0, 0), (4, 0), (1, 1)]);
+        let fold = Fold::X(2);
+
+        let mut paper = dots(&[(0, 0), (4, 0), (1, 1)]);
+        paper.fold(&fold);
+        assert_eq!(paper, dots(&[(0, 0), (1, 1)]));
+
+        let candidates = paper.unfold(&fold);
+        assert_eq!(candidates, dots(&[(0, 0), (4, 0), (1, 1), (3, 1)]));
+        assert!(original.dots.is_subset(&candidates.dots));
+    }
 }
@@ -1,4 +1,8 @@
-use std::collections::HashMap;
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, HashMap};
+
+use num_bigint::BigUint;
 
 const INPUT: &'static str = include_str!("../inputs/day14.txt");
 
@@ -7,47 +11,8 @@ pub(crate) fn run() {
     println!("day 14, output 2: {}", parse1(INPUT, 40));
 }
 
-fn parse1(input: &str, steps: usize) -> usize {
-    let (mut pair_counts, mapping) = parse_input(input);
-
-    for _ in 1..=steps {
-        let mut new_counts: HashMap<Pair, usize> = HashMap::new();
-
-        for (init, (out1, out2)) in &mapping {
-            if pair_counts.contains_key(init) {
-                let initial_count = pair_counts[init];
-                let count = new_counts.entry(out1.clone()).or_default();
-                *count += initial_count;
-                let count = new_counts.entry(out2.clone()).or_default();
-                *count += initial_count;
-            }
-        }
-
-        pair_counts = new_counts;
-    }
-
-    get_max_minus_min(pair_counts)
-}
-
-fn get_max_minus_min(pair_counts: HashMap<Pair, usize>) -> usize {
-    let mut letter_counts: HashMap<char, usize> = HashMap::new();
-    for (pair, count) in pair_counts {
-        *letter_counts.entry(pair.0 .0).or_default() += count;
-        *letter_counts.entry(pair.0 .1).or_default() += count;
-    }
-
-    let mut max = usize::MIN;
-    let mut min = usize::MAX;
-    for (_, count) in letter_counts {
-        let count = (count as f64 / 2.).ceil() as usize;
-        if count < min {
-            min = count
-        } else if count > max {
-            max = count
-        }
-    }
-
-    max - min
+fn parse1(input: &str, steps: u64) -> BigUint {
+    Transitions::new(input).histogram(steps).spread()
 }
 
 fn parse_input(input: &str) -> (HashMap<Pair, usize>, HashMap<Pair, (Pair, Pair)>) {
@@ -80,6 +45,344 @@ fn parse_input(input: &str) -> (HashMap<Pair, usize>, HashMap<Pair, (Pair, Pair)
     (counts, mapping)
 }
 
+/// Indices of the two pairs a pair's insertion rule produces, if it has one.
+type Rule = Option<(usize, usize)>;
+
+/// The template, every pair reachable from it, and for each of those pairs
+/// the indices of the two pairs its insertion rule produces, if it has one.
+fn compile(input: &str) -> (Vec<char>, Vec<Pair>, Vec<Rule>) {
+    let (template, _) = input.split_once("\n\n").unwrap();
+    let (counts, mapping) = parse_input(input);
+
+    let mut pairs: Vec<Pair> = counts.keys().copied().collect();
+    let mut i = 0;
+    while i < pairs.len() {
+        if let Some((left, right)) = mapping.get(&pairs[i]) {
+            for pair in [left, right] {
+                if !pairs.contains(pair) {
+                    pairs.push(*pair);
+                }
+            }
+        }
+        i += 1;
+    }
+
+    let index = |pair: &Pair| pairs.iter().position(|p| p == pair).unwrap();
+    let rules = pairs
+        .iter()
+        .map(|pair| {
+            mapping
+                .get(pair)
+                .map(|(left, right)| (index(left), index(right)))
+        })
+        .collect();
+
+    (template.chars().collect(), pairs, rules)
+}
+
+/// The pair insertion rules compiled into a transition matrix over the pairs
+/// reachable from the template, so the pair counts after any number of steps
+/// are a matrix power away.
+struct Transitions {
+    template: Vec<char>,
+    pairs: Vec<Pair>,
+    /// `matrix[to][from]` is how many copies of `pairs[to]` one step makes
+    /// out of each `pairs[from]`.
+    matrix: Vec<Vec<BigUint>>,
+}
+
+impl Transitions {
+    fn new(input: &str) -> Self {
+        let (template, pairs, rules) = compile(input);
+
+        let mut matrix = vec![vec![BigUint::default(); pairs.len()]; pairs.len()];
+        for (from, rule) in rules.iter().enumerate() {
+            match *rule {
+                Some((left, right)) => {
+                    matrix[left][from] += 1u32;
+                    matrix[right][from] += 1u32;
+                }
+                None => matrix[from][from] += 1u32,
+            }
+        }
+
+        Self {
+            template,
+            pairs,
+            matrix,
+        }
+    }
+
+    /// How many of each of `self.pairs` there are in the template.
+    fn initial_counts(&self) -> Vec<BigUint> {
+        let mut counts = vec![BigUint::default(); self.pairs.len()];
+        for pair in self.template.windows(2) {
+            let i = self.pairs.iter().position(|p| p.0 == (pair[0], pair[1]));
+            counts[i.unwrap()] += 1u32;
+        }
+
+        counts
+    }
+
+    /// How many of each of `self.pairs` there are after `steps` steps, found
+    /// by exponentiation by squaring.
+    fn pair_counts(&self, mut steps: u64) -> Vec<BigUint> {
+        let mut counts = self.initial_counts();
+        let mut power = self.matrix.clone();
+        while steps > 0 {
+            if steps & 1 == 1 {
+                counts = mat_vec_mul(&power, &counts);
+            }
+            steps >>= 1;
+            if steps > 0 {
+                power = mat_mul(&power, &power);
+            }
+        }
+
+        counts
+    }
+
+    /// Exact count of each element given the pair counts. Every element is
+    /// the first of exactly one pair, except the last element of the polymer,
+    /// which never changes from the end of the template.
+    fn count_elements(&self, pair_counts: &[BigUint]) -> ElementHistogram {
+        let mut counts: BTreeMap<char, BigUint> = BTreeMap::new();
+        for (pair, count) in self.pairs.iter().zip(pair_counts) {
+            if count.bits() > 0 {
+                *counts.entry(pair.0 .0).or_default() += count;
+            }
+        }
+
+        if let Some(&last) = self.template.last() {
+            *counts.entry(last).or_default() += 1u32;
+        }
+
+        ElementHistogram { counts }
+    }
+
+    fn histogram(&self, steps: u64) -> ElementHistogram {
+        self.count_elements(&self.pair_counts(steps))
+    }
+
+    /// Histograms after each of steps `0..=steps`.
+    fn history(&self, steps: u64) -> Vec<ElementHistogram> {
+        let mut counts = self.initial_counts();
+        let mut history = vec![self.count_elements(&counts)];
+        for _ in 0..steps {
+            counts = mat_vec_mul(&self.matrix, &counts);
+            history.push(self.count_elements(&counts));
+        }
+
+        history
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ElementHistogram {
+    counts: BTreeMap<char, BigUint>,
+}
+
+impl ElementHistogram {
+    /// The elements sharing the count picked out by `best`, in order.
+    fn extreme<F>(&self, best: F) -> (Vec<char>, BigUint)
+    where
+        F: Fn(&BigUint, &BigUint) -> bool,
+    {
+        let mut extreme: Option<&BigUint> = None;
+        for count in self.counts.values() {
+            if extreme.is_none_or(|e| best(count, e)) {
+                extreme = Some(count);
+            }
+        }
+
+        let count = extreme.cloned().unwrap_or_default();
+        let elements = self
+            .counts
+            .iter()
+            .filter(|&(_, c)| *c == count)
+            .map(|(&e, _)| e)
+            .collect();
+
+        (elements, count)
+    }
+
+    fn most_common(&self) -> (Vec<char>, BigUint) {
+        self.extreme(|a, b| a > b)
+    }
+
+    fn least_common(&self) -> (Vec<char>, BigUint) {
+        self.extreme(|a, b| a < b)
+    }
+
+    fn spread(&self) -> BigUint {
+        self.most_common().1 - self.least_common().1
+    }
+}
+
+/// The polymer after a fixed number of steps, without building it. Each
+/// pair expands into everything up to but not including its second element,
+/// and the expanded length of every pair at every depth is precomputed so
+/// any position can be found by descending through the insertions.
+struct Expansion {
+    template: Vec<char>,
+    pairs: Vec<Pair>,
+    rules: Vec<Rule>,
+    /// `lengths[depth][pair]`, saturating at `u64::MAX`.
+    lengths: Vec<Vec<u64>>,
+}
+
+impl Expansion {
+    fn new(input: &str, steps: usize) -> Self {
+        let (template, pairs, rules) = compile(input);
+
+        let mut lengths: Vec<Vec<u64>> = vec![vec![1; pairs.len()]];
+        for depth in 1..=steps {
+            let prev = &lengths[depth - 1];
+            let next = rules
+                .iter()
+                .enumerate()
+                .map(|(i, rule)| match *rule {
+                    Some((left, right)) => prev[left].saturating_add(prev[right]),
+                    None => prev[i],
+                })
+                .collect();
+            lengths.push(next);
+        }
+
+        Self {
+            template,
+            pairs,
+            rules,
+            lengths,
+        }
+    }
+
+    fn steps(&self) -> usize {
+        self.lengths.len() - 1
+    }
+
+    /// The template's pairs, by index into `self.pairs`.
+    fn roots(&self) -> impl Iterator<Item = usize> + '_ {
+        self.template
+            .windows(2)
+            .map(move |w| self.pairs.iter().position(|p| p.0 == (w[0], w[1])).unwrap())
+    }
+
+    fn len(&self) -> u64 {
+        let depth = self.steps();
+        self.roots()
+            .fold(1, |len: u64, i| len.saturating_add(self.lengths[depth][i]))
+    }
+
+    fn char_at(&self, mut k: u64) -> Option<char> {
+        let depth = self.steps();
+        for root in self.roots() {
+            let len = self.lengths[depth][root];
+            if k < len {
+                return Some(self.descend(root, depth, k));
+            }
+            k -= len;
+        }
+
+        if k == 0 {
+            self.template.last().copied()
+        } else {
+            None
+        }
+    }
+
+    fn descend(&self, mut pair: usize, mut depth: usize, mut k: u64) -> char {
+        while let (Some((left, right)), true) = (self.rules[pair], depth > 0) {
+            depth -= 1;
+            let left_len = self.lengths[depth][left];
+            if k < left_len {
+                pair = left;
+            } else {
+                k -= left_len;
+                pair = right;
+            }
+        }
+
+        self.pairs[pair].0 .0
+    }
+
+    /// The characters at positions `start..end`, clipped to the polymer.
+    fn substring(&self, start: u64, end: u64) -> String {
+        let depth = self.steps();
+        let mut out = String::new();
+        let mut offset = 0u64;
+        for root in self.roots() {
+            let len = self.lengths[depth][root];
+            if offset >= end {
+                break;
+            }
+            if offset.saturating_add(len) > start {
+                self.emit(
+                    root,
+                    depth,
+                    start.saturating_sub(offset),
+                    end - offset,
+                    &mut out,
+                );
+            }
+            offset = offset.saturating_add(len);
+        }
+
+        if (start..end).contains(&offset) {
+            out.extend(self.template.last());
+        }
+
+        out
+    }
+
+    /// Push the part of `pair` expanded to `depth` that lies in `start..end`,
+    /// relative to the start of the pair, visiting only overlapping subtrees.
+    fn emit(&self, pair: usize, depth: usize, start: u64, end: u64, out: &mut String) {
+        match (self.rules[pair], depth) {
+            (Some((left, right)), 1..) => {
+                let left_len = self.lengths[depth - 1][left];
+                if start < left_len {
+                    self.emit(left, depth - 1, start, end.min(left_len), out);
+                }
+                if end > left_len {
+                    let start = start.saturating_sub(left_len);
+                    self.emit(right, depth - 1, start, end - left_len, out);
+                }
+            }
+            _ => {
+                if start == 0 && end > 0 {
+                    out.push(self.pairs[pair].0 .0);
+                }
+            }
+        }
+    }
+}
+
+fn mat_mul(a: &[Vec<BigUint>], b: &[Vec<BigUint>]) -> Vec<Vec<BigUint>> {
+    let n = a.len();
+    let mut out = vec![vec![BigUint::default(); n]; n];
+    for i in 0..n {
+        for k in 0..n {
+            if a[i][k].bits() == 0 {
+                continue;
+            }
+            for j in 0..n {
+                if b[k][j].bits() != 0 {
+                    out[i][j] += &a[i][k] * &b[k][j];
+                }
+            }
+        }
+    }
+
+    out
+}
+
+fn mat_vec_mul(a: &[Vec<BigUint>], v: &[BigUint]) -> Vec<BigUint> {
+    a.iter()
+        .map(|row| row.iter().zip(v).map(|(x, y)| x * y).sum())
+        .collect()
+}
+
 #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
 struct Pair((char, char));
 
@@ -108,11 +411,97 @@ CN -> C";
 
     #[test]
     fn first() {
-        assert_eq!(parse1(INPUTS, 10), 1588);
+        assert_eq!(parse1(INPUTS, 10), BigUint::from(1588u32));
     }
 
     #[test]
     fn second() {
-        assert_eq!(parse1(INPUTS, 40), 2188189693529);
+        assert_eq!(parse1(INPUTS, 40), BigUint::from(2188189693529u64));
+    }
+
+    #[test]
+    fn matrix_power() {
+        let transitions = Transitions::new(INPUTS);
+        let counts = transitions.histogram(10).counts;
+        assert_eq!(counts[&'B'], BigUint::from(1749u32));
+        assert_eq!(counts[&'C'], BigUint::from(298u32));
+        assert_eq!(counts[&'H'], BigUint::from(161u32));
+        assert_eq!(counts[&'N'], BigUint::from(865u32));
+
+        let counts = transitions.histogram(40).counts;
+        assert_eq!(counts[&'B'], BigUint::from(2192039569602u64));
+        assert_eq!(counts[&'H'], BigUint::from(3849876073u64));
+
+        // Each step doubles the number of pairs, so the length after n steps
+        // is (4 - 1) * 2^n + 1
+        let counts = transitions.histogram(1000).counts;
+        let length: BigUint = counts.values().sum();
+        assert_eq!(length, (BigUint::from(3u32) << 1000) + 1u32);
+    }
+
+    #[test]
+    fn random_access() {
+        let polymers = [
+            "NNCB",
+            "NCNBCHB",
+            "NBCCNBBBCBHCB",
+            "NBBBCNCCNBBNBNBBCHBHHBCHB",
+            "NBBNBNBBCCNBCNCCNBBNBBNBBBNBBNBBCBHCBHHNHCBBCBHCB",
+        ];
+
+        for (steps, polymer) in polymers.iter().enumerate() {
+            let expansion = Expansion::new(INPUTS, steps);
+            assert_eq!(expansion.len(), polymer.len() as u64);
+            assert_eq!(expansion.substring(0, expansion.len()), *polymer);
+            assert_eq!(expansion.substring(3, 6), polymer[3..polymer.len().min(6)]);
+
+            for (k, c) in polymer.chars().enumerate() {
+                assert_eq!(expansion.char_at(k as u64), Some(c));
+            }
+            assert_eq!(expansion.char_at(polymer.len() as u64), None);
+        }
+
+        let expansion = Expansion::new(INPUTS, 40);
+        assert_eq!(expansion.len(), 3 * (1 << 40) + 1);
+        assert_eq!(expansion.char_at(expansion.len() - 1), Some('B'));
+
+        let start = 1_234_567_890_123;
+        let window = expansion.substring(start, start + 20);
+        assert_eq!(window.len(), 20);
+        for (i, c) in window.chars().enumerate() {
+            assert_eq!(expansion.char_at(start + i as u64), Some(c));
+        }
+    }
+
+    #[test]
+    fn histogram() {
+        let transitions = Transitions::new(INPUTS);
+        let history = transitions.history(10);
+        assert_eq!(history.len(), 11);
+        assert_eq!(history[10], transitions.histogram(10));
+
+        // NNCB has two Ns and one each of C and B
+        assert_eq!(history[0].most_common(), (vec!['N'], BigUint::from(2u32)));
+        assert_eq!(
+            history[0].least_common(),
+            (vec!['B', 'C'], BigUint::from(1u32))
+        );
+
+        // NCNBCHB
+        assert_eq!(
+            history[1].most_common(),
+            (vec!['B', 'C', 'N'], BigUint::from(2u32))
+        );
+        assert_eq!(history[1].least_common(), (vec!['H'], BigUint::from(1u32)));
+        assert_eq!(history[1].spread(), BigUint::from(1u32));
+
+        assert_eq!(
+            history[10].most_common(),
+            (vec!['B'], BigUint::from(1749u32))
+        );
+        assert_eq!(
+            history[10].least_common(),
+            (vec!['H'], BigUint::from(161u32))
+        );
     }
 }
@@ -1,6 +1,7 @@
+#![allow(dead_code)]
+
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
-use std::collections::{HashSet, VecDeque};
 
 const INPUT: &'static str = include_str!("../inputs/day15.txt");
 
@@ -10,83 +11,293 @@ pub(crate) fn run() {
 }
 
 fn parse1(input: &str) -> u32 {
-    let grid: Vec<Vec<u32>> = input
-        .lines()
-        .map(|line| line.chars().map(|c| c.to_digit(10).unwrap()).collect())
-        .collect();
+    let grid = parse_grid(input);
 
-    shortest_path(grid).expect("No path")
+    shortest_path(&grid, Search::Dial).expect("No path")
 }
 
 fn parse2(input: &str) -> u32 {
-    let small_grid: Vec<Vec<u32>> = input
+    let grid = Tiled::new(parse_grid(input));
+
+    shortest_path(&grid, Search::Dial).expect("no path")
+}
+
+fn parse_grid(input: &str) -> Vec<Vec<u32>> {
+    input
         .lines()
         .map(|line| line.chars().map(|c| c.to_digit(10).unwrap()).collect())
-        .collect();
-
-    let n_rows = small_grid.len();
-    let n_cols = small_grid[0].len();
-
-    let mut grid = vec![vec![0; 5 * n_cols]; 5 * n_rows];
-    for row in 0..5 * n_rows {
-        for col in 0..5 * n_cols {
-            let inc = (row / n_rows) + (col / n_cols);
-            let mut val = small_grid[row % n_rows][col % n_cols] + inc as u32;
-            if val > 9 {
-                val -= 9;
-            }
-            grid[row][col] = val;
+        .collect()
+}
+
+fn shortest_path<M: RiskMap>(grid: &M, search: Search) -> Option<u32> {
+    let goal = (grid.n_rows() - 1, grid.n_cols() - 1);
+    find_path(grid, (0, 0), goal, search, &Rules::default()).map(|route| route.cost)
+}
+
+/// The risk of entering each cell of a grid.
+trait RiskMap {
+    fn n_rows(&self) -> usize;
+    fn n_cols(&self) -> usize;
+    fn risk(&self, row: usize, col: usize) -> u32;
+
+    /// An upper bound on `risk`. Risks are single digits unless a map says
+    /// otherwise.
+    fn max_risk(&self) -> u32 {
+        9
+    }
+}
+
+impl RiskMap for Vec<Vec<u32>> {
+    fn n_rows(&self) -> usize {
+        self.len()
+    }
+
+    fn n_cols(&self) -> usize {
+        self[0].len()
+    }
+
+    fn risk(&self, row: usize, col: usize) -> u32 {
+        self[row][col]
+    }
+}
+
+/// A tile repeated `factor.0` times down and `factor.1` times across, with
+/// each repeat's risks raised by `increment` for every tile it is away from
+/// the top left. Risks are worked out on demand rather than stored.
+struct Tiled {
+    tile: Vec<Vec<u32>>,
+    factor: (usize, usize),
+    increment: Increment,
+}
+
+impl Tiled {
+    /// The puzzle's tiling: five by five, one more risky per tile, 9 wraps to 1.
+    fn new(tile: Vec<Vec<u32>>) -> Self {
+        Self {
+            tile,
+            factor: (5, 5),
+            increment: Increment::Wrap { step: 1, max: 9 },
+        }
+    }
+}
+
+impl RiskMap for Tiled {
+    fn n_rows(&self) -> usize {
+        self.tile.len() * self.factor.0
+    }
+
+    fn n_cols(&self) -> usize {
+        self.tile[0].len() * self.factor.1
+    }
+
+    fn risk(&self, row: usize, col: usize) -> u32 {
+        let n_rows = self.tile.len();
+        let n_cols = self.tile[0].len();
+        let tiles = (row / n_rows + col / n_cols) as u32;
+
+        self.increment
+            .apply(self.tile[row % n_rows][col % n_cols], tiles)
+    }
+
+    fn max_risk(&self) -> u32 {
+        match self.increment {
+            Increment::Wrap { max, .. } | Increment::Saturate { max, .. } => max,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Increment {
+    /// Add `step` per tile, going past `max` back round to 1.
+    Wrap { step: u32, max: u32 },
+    /// Add `step` per tile, stopping at `max`.
+    Saturate { step: u32, max: u32 },
+}
+
+impl Increment {
+    fn apply(&self, risk: u32, tiles: u32) -> u32 {
+        match *self {
+            Increment::Wrap { step, max } => (risk + step * tiles - 1) % max + 1,
+            Increment::Saturate { step, max } => (risk + step * tiles).min(max),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Moves {
+    Four,
+    Eight,
+    Knight,
+}
+
+impl Moves {
+    fn offsets(&self) -> &'static [(isize, isize)] {
+        match self {
+            Moves::Four => &[(-1, 0), (1, 0), (0, -1), (0, 1)],
+            Moves::Eight => &[
+                (-1, -1),
+                (-1, 0),
+                (-1, 1),
+                (0, -1),
+                (0, 1),
+                (1, -1),
+                (1, 0),
+                (1, 1),
+            ],
+            Moves::Knight => &[
+                (-2, -1),
+                (-2, 1),
+                (-1, -2),
+                (-1, 2),
+                (1, -2),
+                (1, 2),
+                (2, -1),
+                (2, 1),
+            ],
         }
     }
 
-    shortest_path(grid).expect("no path")
+    /// The furthest one move can go in Manhattan distance.
+    fn reach(&self) -> usize {
+        match self {
+            Moves::Four => 1,
+            Moves::Eight => 2,
+            Moves::Knight => 3,
+        }
+    }
 }
 
-fn shortest_path(grid: Vec<Vec<u32>>) -> Option<u32> {
-    let max_row = grid.len() - 1;
-    let max_col = grid[0].len() - 1;
-    let goal = (max_row, max_col);
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CostModel {
+    /// Pay the risk of every cell moved into, so not the start.
+    Entry,
+    /// Pay the risk of every cell moved out of, so not the goal.
+    Exit,
+    /// The riskiest cell moved into; the cheapest route is the safest one.
+    Minimax,
+}
 
-    let mut costs = vec![vec![u32::MAX; max_row + 1]; max_col + 1];
+impl CostModel {
+    fn step(&self, cost: u32, from: u32, to: u32) -> u32 {
+        match self {
+            CostModel::Entry => cost + to,
+            CostModel::Exit => cost + from,
+            CostModel::Minimax => cost.max(to),
+        }
+    }
+}
 
-    let mut heap = BinaryHeap::new();
+struct Rules {
+    moves: Moves,
+    cost: CostModel,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            moves: Moves::Four,
+            cost: CostModel::Entry,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Search {
+    Dijkstra,
+    /// Guided by the fewest moves it could take to reach the goal, which
+    /// never overestimates as every move costs at least 1. Under `Minimax`
+    /// costs don't add up, so this falls back to Dijkstra.
+    AStar,
+    /// Dijkstra with a bucket queue in place of the heap. Risks are small, so
+    /// every cost still waiting to be visited is within `max_risk` of the
+    /// cheapest, and a ring of that many buckets is enough.
+    Dial,
+}
 
-    costs[0][0] = 0;
-    heap.push(State {
+#[derive(Debug, PartialEq)]
+struct Route {
+    cost: u32,
+    /// Every cell from the start to the goal, inclusive.
+    path: Vec<(usize, usize)>,
+}
+
+fn find_path<M: RiskMap>(
+    grid: &M,
+    start: (usize, usize),
+    goal: (usize, usize),
+    search: Search,
+    rules: &Rules,
+) -> Option<Route> {
+    let n_rows = grid.n_rows();
+    let n_cols = grid.n_cols();
+    let offsets = rules.moves.offsets();
+    let heuristic = |(row, col): (usize, usize)| match (search, rules.cost) {
+        (Search::AStar, CostModel::Entry | CostModel::Exit) => {
+            (row.abs_diff(goal.0) + col.abs_diff(goal.1)).div_ceil(rules.moves.reach()) as u32
+        }
+        _ => 0,
+    };
+
+    // Flat `[row * n_cols + col]` so large grids stay compact. Each cell
+    // remembers which offset it was reached by rather than where from.
+    let mut costs = vec![u32::MAX; n_rows * n_cols];
+    let mut previous = vec![u8::MAX; n_rows * n_cols];
+
+    let mut frontier = match search {
+        Search::Dijkstra | Search::AStar => Frontier::Heap(BinaryHeap::new()),
+        Search::Dial => Frontier::buckets(grid.max_risk()),
+    };
+
+    costs[start.0 * n_cols + start.1] = 0;
+    frontier.push(State {
+        priority: heuristic(start),
         cost: 0,
-        pos: (0, 0),
+        pos: start,
     });
 
-    while let Some(State { cost, pos }) = heap.pop() {
-        // If we are at the goal, return
+    while let Some(State { cost, pos, .. }) = frontier.pop() {
+        // If we are at the goal, walk back along the cheapest route
         if pos == goal {
-            return Some(cost);
+            let mut path = vec![goal];
+            let (mut row, mut col) = goal;
+            while (row, col) != start {
+                let (d_row, d_col) = offsets[previous[row * n_cols + col] as usize];
+                row = (row as isize - d_row) as usize;
+                col = (col as isize - d_col) as usize;
+                path.push((row, col));
+            }
+            path.reverse();
+
+            return Some(Route { cost, path });
         }
 
         let (row, col) = pos;
-        // Ignore if we've alread found a better way. This is because we don't update the costs in the heap
-        if cost > costs[row][col] {
+        // Ignore if we've alread found a better way. This is because we don't update the costs in the queue
+        if cost > costs[row * n_cols + col] {
             continue;
         }
 
-        for (next_row, next_col) in [
-            (row.checked_sub(1).unwrap_or(0), col),
-            (row + 1, col),
-            (row, col.checked_sub(1).unwrap_or(0)),
-            (row, col + 1),
-        ] {
-            if (next_row, next_col) == pos || next_row > max_row || next_col > max_col {
+        for (direction, &(d_row, d_col)) in offsets.iter().enumerate() {
+            let next_row = row.wrapping_add(d_row as usize);
+            let next_col = col.wrapping_add(d_col as usize);
+            if next_row >= n_rows || next_col >= n_cols {
                 continue;
             }
 
-            let next_cost = cost + grid[next_row][next_col];
+            let next = next_row * n_cols + next_col;
+            let next_cost =
+                rules
+                    .cost
+                    .step(cost, grid.risk(row, col), grid.risk(next_row, next_col));
 
-            if next_cost < costs[next_row][next_col] {
-                heap.push(State {
+            if next_cost < costs[next] {
+                frontier.push(State {
+                    priority: next_cost + heuristic((next_row, next_col)),
                     cost: next_cost,
                     pos: (next_row, next_col),
                 });
-                costs[next_row][next_col] = next_cost;
+                costs[next] = next_cost;
+                previous[next] = direction as u8;
             }
         }
     }
@@ -94,8 +305,89 @@ fn shortest_path(grid: Vec<Vec<u32>>) -> Option<u32> {
     None
 }
 
+/// Draw the grid with the risk of each cell on `path` and `.` everywhere else.
+/// Risks above 9 pad every cell to the same width, with spaces between them.
+fn render<M: RiskMap>(grid: &M, path: &[(usize, usize)]) -> String {
+    let mut lines = vec![vec![String::from("."); grid.n_cols()]; grid.n_rows()];
+    for &(row, col) in path {
+        lines[row][col] = grid.risk(row, col).to_string();
+    }
+
+    let width = lines.iter().flatten().map(String::len).max().unwrap_or(1);
+    let separator = if width > 1 { " " } else { "" };
+    lines
+        .iter()
+        .map(|line| {
+            line.iter()
+                .map(|cell| format!("{:>1$}", cell, width))
+                .collect::<Vec<String>>()
+                .join(separator)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Cells waiting to be visited, cheapest first.
+enum Frontier {
+    Heap(BinaryHeap<State>),
+    /// A ring of buckets indexed by priority. `current` is the lowest
+    /// priority that might still have anything in it.
+    Buckets {
+        buckets: Vec<Vec<State>>,
+        current: u32,
+        len: usize,
+    },
+}
+
+impl Frontier {
+    fn buckets(max_risk: u32) -> Self {
+        Frontier::Buckets {
+            buckets: vec![vec![]; max_risk as usize + 1],
+            current: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, state: State) {
+        match self {
+            Frontier::Heap(heap) => heap.push(state),
+            Frontier::Buckets { buckets, len, .. } => {
+                let n = buckets.len();
+                buckets[state.priority as usize % n].push(state);
+                *len += 1;
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<State> {
+        match self {
+            Frontier::Heap(heap) => heap.pop(),
+            Frontier::Buckets {
+                buckets,
+                current,
+                len,
+            } => {
+                if *len == 0 {
+                    return None;
+                }
+
+                let n = buckets.len();
+                loop {
+                    if let Some(state) = buckets[*current as usize % n].pop() {
+                        *len -= 1;
+                        return Some(state);
+                    }
+                    *current += 1;
+                }
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 struct State {
+    /// Cost so far plus the heuristic's estimate of the cost to go.
+    priority: u32,
     cost: u32,
     pos: (usize, usize),
 }
@@ -103,8 +395,8 @@ struct State {
 impl Ord for State {
     fn cmp(&self, other: &Self) -> Ordering {
         other
-            .cost
-            .cmp(&self.cost)
+            .priority
+            .cmp(&self.priority)
             .then_with(|| self.pos.cmp(&other.pos))
     }
 }
@@ -139,4 +431,175 @@ mod test {
     fn second() {
         assert_eq!(parse2(INPUTS), 315);
     }
+
+    #[test]
+    fn route() {
+        let grid = parse_grid(INPUTS);
+        let dijkstra =
+            find_path(&grid, (0, 0), (9, 9), Search::Dijkstra, &Rules::default()).unwrap();
+        let a_star = find_path(&grid, (0, 0), (9, 9), Search::AStar, &Rules::default()).unwrap();
+
+        assert_eq!(dijkstra.cost, 40);
+        assert_eq!(a_star.cost, 40);
+        for route in [&dijkstra, &a_star] {
+            assert_eq!(route.path[0], (0, 0));
+            assert_eq!(route.path[route.path.len() - 1], (9, 9));
+            let risk: u32 = route.path[1..].iter().map(|&(r, c)| grid[r][c]).sum();
+            assert_eq!(risk, route.cost);
+        }
+
+        assert_eq!(
+            render(&grid, &dijkstra.path),
+            "1.........
+1.........
+2136511...
+......15..
+.......1..
+.......13.
+........2.
+........3.
+........21
+.........1"
+        );
+        assert_eq!(
+            render(&vec![vec![1, 12], vec![3, 4]], &[(0, 0), (0, 1), (1, 1)]),
+            " 1 12\n .  4"
+        );
+
+        // Arbitrary endpoints, in either direction
+        let there = find_path(&grid, (9, 0), (2, 4), Search::AStar, &Rules::default()).unwrap();
+        let back = find_path(&grid, (2, 4), (9, 0), Search::Dijkstra, &Rules::default()).unwrap();
+        assert_eq!(there.cost - grid[2][4] + grid[9][0], back.cost);
+        assert_eq!(
+            find_path(&grid, (3, 3), (3, 3), Search::AStar, &Rules::default())
+                .unwrap()
+                .path,
+            vec![(3, 3)]
+        );
+    }
+
+    #[test]
+    fn bucket_queue() {
+        let grid = parse_grid(INPUTS);
+        let tiled = Tiled::new(grid.clone());
+        assert_eq!(tiled.risk(0, 10), 2);
+        assert_eq!(tiled.risk(49, 49), 9);
+        assert_eq!(tiled.risk(30, 22), 2);
+
+        for search in [Search::Dijkstra, Search::AStar, Search::Dial] {
+            assert_eq!(shortest_path(&grid, search), Some(40));
+            assert_eq!(shortest_path(&tiled, search), Some(315));
+        }
+
+        let route = find_path(&tiled, (49, 0), (0, 49), Search::Dial, &Rules::default()).unwrap();
+        let risk: u32 = route.path[1..].iter().map(|&(r, c)| tiled.risk(r, c)).sum();
+        assert_eq!(risk, route.cost);
+        assert_eq!(
+            route.cost,
+            find_path(
+                &tiled,
+                (49, 0),
+                (0, 49),
+                Search::Dijkstra,
+                &Rules::default()
+            )
+            .unwrap()
+            .cost
+        );
+    }
+
+    #[test]
+    fn rules() {
+        let grid = parse_grid(INPUTS);
+        let cost = |moves, cost, search| {
+            find_path(&grid, (0, 0), (9, 9), search, &Rules { moves, cost })
+                .unwrap()
+                .cost
+        };
+
+        for search in [Search::Dijkstra, Search::AStar, Search::Dial] {
+            assert_eq!(cost(Moves::Four, CostModel::Exit, search), 40);
+            assert_eq!(cost(Moves::Four, CostModel::Minimax, search), 6);
+            assert_eq!(cost(Moves::Eight, CostModel::Entry, search), 20);
+            assert_eq!(cost(Moves::Eight, CostModel::Minimax, search), 3);
+            assert_eq!(cost(Moves::Knight, CostModel::Entry, search), 11);
+            assert_eq!(cost(Moves::Knight, CostModel::Minimax, search), 3);
+        }
+
+        let route = find_path(
+            &grid,
+            (0, 0),
+            (9, 9),
+            Search::AStar,
+            &Rules {
+                moves: Moves::Knight,
+                cost: CostModel::Exit,
+            },
+        )
+        .unwrap();
+        assert_eq!(route.cost, 11);
+        let risk: u32 = route.path[..route.path.len() - 1]
+            .iter()
+            .map(|&(r, c)| grid[r][c])
+            .sum();
+        assert_eq!(risk, 11);
+
+        let tiled = Tiled {
+            tile: grid,
+            factor: (2, 3),
+            increment: Increment::Saturate { step: 2, max: 9 },
+        };
+        assert_eq!((tiled.n_rows(), tiled.n_cols()), (20, 30));
+        assert_eq!(tiled.risk(5, 25), 6);
+        assert_eq!(tiled.risk(19, 29), 7);
+        assert_eq!(shortest_path(&tiled, Search::Dial), Some(236));
+    }
+
+    /// Pseudo-random risks from a linear congruential generator, so the
+    /// benchmark doesn't need a crate or a fixture.
+    fn synthetic(size: usize) -> Vec<Vec<u32>> {
+        let mut seed: u64 = 15;
+        (0..size)
+            .map(|_| {
+                (0..size)
+                    .map(|_| {
+                        seed = seed
+                            .wrapping_mul(6364136223846793005)
+                            .wrapping_add(1442695040888963407);
+                        (seed >> 33) as u32 % 9 + 1
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// `cargo test --release day15::test::benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn benchmark() {
+        use std::time::Instant;
+
+        let tiled = Tiled::new(parse_grid(INPUT));
+        let synthetic = synthetic(5000);
+
+        for search in [Search::Dijkstra, Search::AStar, Search::Dial] {
+            let start = Instant::now();
+            let cost = shortest_path(&tiled, search);
+            println!(
+                "tiled     {:?}: {:?} in {:?}",
+                search,
+                cost,
+                start.elapsed()
+            );
+
+            let start = Instant::now();
+            let cost = shortest_path(&synthetic, search);
+            println!(
+                "5000x5000 {:?}: {:?} in {:?}",
+                search,
+                cost,
+                start.elapsed()
+            );
+        }
+    }
 }
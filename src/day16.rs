@@ -1,4 +1,9 @@
-use std::collections::VecDeque;
+#![allow(dead_code)]
+
+use num_bigint::BigUint;
+use std::fmt;
+use std::io::{self, BufReader, Read};
+use std::str::FromStr;
 
 const INPUT: &'static str = include_str!("../inputs/day16.txt");
 
@@ -8,41 +13,123 @@ pub(crate) fn run() {
 }
 
 fn parse1(input: &str) -> usize {
-    let mut buffer = BitBuffer::from_hex_str(input);
-    let packet = Packet::from_buffer(&mut buffer);
+    version_sum(input.as_bytes(), Encoding::Hex).unwrap()
+}
 
-    let mut queue = VecDeque::from(vec![packet]);
-    let mut sum = 0;
+fn parse2(input: &str) -> BigUint {
+    evaluate(input.as_bytes(), Encoding::Hex).unwrap()
+}
 
-    while !queue.is_empty() {
-        match queue.pop_front().unwrap() {
-            Packet::Literal { version, value: _ } => sum += version as usize,
-            Packet::Operator {
-                version,
-                type_id: _,
-                sub_packets,
-            } => {
-                sum += version as usize;
-                queue.extend(sub_packets);
-            }
-        }
+/// Operator type ids with their names and the symbols used in expressions.
+const OPERATORS: &[(u8, &str, &str)] = &[
+    (0, "sum", "+"),
+    (1, "product", "*"),
+    (2, "min", "min"),
+    (3, "max", "max"),
+    (5, "gt", ">"),
+    (6, "lt", "<"),
+    (7, "eq", "=="),
+];
+
+fn operator(type_id: u8) -> (&'static str, &'static str) {
+    OPERATORS
+        .iter()
+        .find(|&&(id, _, _)| id == type_id)
+        .map(|&(_, name, symbol)| (name, symbol))
+        .unwrap()
+}
+
+fn is_operator(type_id: u8) -> bool {
+    OPERATORS.iter().any(|&(id, _, _)| id == type_id)
+}
+
+/// Whether the operator `type_id` can take `count` operands: exactly two for
+/// comparisons and at least one for everything else.
+fn check_arity(type_id: u8, count: usize) -> bool {
+    match type_id {
+        5..=7 => count == 2,
+        _ => count > 0,
     }
+}
 
-    sum
+/// How an operator says where its sub-packets end.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum LengthType {
+    /// A 15-bit total length of the sub-packets in bits.
+    Bits,
+    /// An 11-bit count of the sub-packets.
+    Count,
 }
 
-fn parse2(input: &str) -> usize {
-    let mut buffer = BitBuffer::from_hex_str(input);
-    let packet = Packet::from_buffer(&mut buffer);
+/// Why a packet couldn't be encoded.
+#[derive(Debug, PartialEq)]
+enum EncodeError {
+    /// Versions only have 3 bits.
+    Version(u8),
+    /// Type ids only have 3 bits, and 4 is for literals.
+    TypeId(u8),
+    /// More bits of sub-packets than a 15-bit length can hold.
+    TooLong(usize),
+    /// More sub-packets than an 11-bit count can hold.
+    TooMany(usize),
+    /// An operator with the wrong number of sub-packets for its type.
+    Arity { type_id: u8, count: usize },
+}
 
-    packet.value()
+/// Why a transmission couldn't be decoded, with the offset in bits where the
+/// problem starts. Errors about a packet point at its first bit.
+#[derive(Debug, PartialEq)]
+enum DecodeError {
+    /// The transmission ended partway through a packet.
+    Truncated {
+        offset: usize,
+    },
+    InvalidHex {
+        offset: usize,
+        found: char,
+    },
+    BadTypeId {
+        offset: usize,
+        type_id: u8,
+    },
+    /// A comparison without exactly two sub-packets, or another operator
+    /// without any.
+    Arity {
+        offset: usize,
+        type_id: u8,
+        count: usize,
+    },
+    /// The sub-packets ran past the length in bits the operator gave.
+    LengthMismatch {
+        offset: usize,
+        expected: usize,
+        actual: usize,
+    },
+    /// A set bit after the end of the outermost packet, in strict mode.
+    Padding {
+        offset: usize,
+    },
+    /// Reading the transmission itself failed.
+    Io {
+        offset: usize,
+        kind: io::ErrorKind,
+    },
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// What to allow after the outermost packet.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Padding {
+    /// Ignore anything.
+    Lenient,
+    /// Only zeros.
+    Strict,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 enum Packet {
     Literal {
         version: u8,
-        value: usize,
+        value: BigUint,
     },
     Operator {
         version: u8,
@@ -52,152 +139,720 @@ enum Packet {
 }
 
 impl Packet {
-    fn from_buffer(buffer: &mut BitBuffer) -> Self {
-        let version = buffer.read_bits(3) as u8;
-        let type_id = buffer.read_bits(3) as u8;
-        let packet = match type_id {
-            4 => Packet::Literal {
-                version,
-                value: Packet::parse_literal(buffer),
-            },
-            _ => Packet::Operator {
-                version,
-                type_id,
-                sub_packets: Packet::parse_subpackets(buffer),
-            },
-        };
+    fn decode(hex_str: &str) -> Result<Self, DecodeError> {
+        Packet::decode_with(hex_str, Padding::Lenient)
+    }
+
+    fn decode_with(hex_str: &str, padding: Padding) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder::new(hex_str.as_bytes(), Encoding::Hex);
+        let packet = Packet::from_events(&mut decoder)?;
+
+        if padding == Padding::Strict {
+            decoder.check_padding()?;
+        }
 
-        packet
+        Ok(packet)
     }
 
-    fn parse_literal(buffer: &mut BitBuffer) -> usize {
-        let mut value = 0;
-        loop {
-            let end = buffer.read_bits(1) == 0;
-            value <<= 4;
-            value |= buffer.read_bits(4);
-            if end {
-                break;
+    /// Build the tree for the next packet from a stream of events.
+    fn from_events<R: Read>(decoder: &mut Decoder<R>) -> Result<Self, DecodeError> {
+        let mut open: Vec<(u8, u8, Vec<Packet>)> = vec![];
+        for event in decoder {
+            let packet = match event? {
+                Event::Start {
+                    version, type_id, ..
+                } => {
+                    open.push((version, type_id, vec![]));
+                    continue;
+                }
+                Event::Literal { version, value, .. } => Packet::Literal { version, value },
+                Event::End { .. } => {
+                    let (version, type_id, sub_packets) = open.pop().unwrap();
+                    Packet::Operator {
+                        version,
+                        type_id,
+                        sub_packets,
+                    }
+                }
+            };
+
+            match open.last_mut() {
+                None => return Ok(packet),
+                Some((_, _, sub_packets)) => sub_packets.push(packet),
             }
         }
-        value
+
+        unreachable!("the decoder ends with the outermost packet or an error")
     }
 
-    fn parse_subpackets(buffer: &mut BitBuffer) -> Vec<Packet> {
-        let (mut n_bits, n_packets) = match buffer.read_bits(1) {
-            0 => (buffer.read_bits(15), 0),
-            1 => (0, buffer.read_bits(11)),
-            _ => unreachable!(),
-        };
+    /// The hex transmission for this packet, padded with zeros to a whole
+    /// number of bytes. Every operator uses the same `length_type`.
+    fn encode(&self, length_type: LengthType) -> Result<String, EncodeError> {
+        let mut writer = BitWriter::default();
+        self.write(&mut writer, length_type)?;
+        Ok(writer.to_hex_str())
+    }
 
-        let mut sub_packets = Vec::new();
+    fn write(&self, writer: &mut BitWriter, length_type: LengthType) -> Result<(), EncodeError> {
+        match self {
+            Packet::Literal { version, value } => {
+                if *version > 7 {
+                    return Err(EncodeError::Version(*version));
+                }
+                writer.write_bits(*version as usize, 3);
+                writer.write_bits(4, 3);
 
-        while n_bits > 0 {
-            let initial_pos = 4 * buffer.index + buffer.bit;
-            sub_packets.push(Packet::from_buffer(buffer));
-            let final_pos = 4 * buffer.index + buffer.bit;
-            n_bits -= final_pos - initial_pos;
-        }
+                // Groups of 4 bits, most significant first, with no leading
+                // zero groups but always at least one group
+                let groups = value.to_radix_be(16);
+                for (i, &group) in groups.iter().enumerate() {
+                    writer.write_bits((i + 1 < groups.len()) as usize, 1);
+                    writer.write_bits(group as usize, 4);
+                }
+            }
+            Packet::Operator {
+                version,
+                type_id,
+                sub_packets,
+            } => {
+                if *version > 7 {
+                    return Err(EncodeError::Version(*version));
+                }
+                if !is_operator(*type_id) {
+                    return Err(EncodeError::TypeId(*type_id));
+                }
+                if !check_arity(*type_id, sub_packets.len()) {
+                    return Err(EncodeError::Arity {
+                        type_id: *type_id,
+                        count: sub_packets.len(),
+                    });
+                }
+                writer.write_bits(*version as usize, 3);
+                writer.write_bits(*type_id as usize, 3);
+
+                let mut contents = BitWriter::default();
+                for packet in sub_packets {
+                    packet.write(&mut contents, length_type)?;
+                }
 
-        for _ in 0..n_packets {
-            sub_packets.push(Packet::from_buffer(buffer));
+                match length_type {
+                    LengthType::Bits => {
+                        if contents.bits.len() >= 1 << 15 {
+                            return Err(EncodeError::TooLong(contents.bits.len()));
+                        }
+                        writer.write_bits(0, 1);
+                        writer.write_bits(contents.bits.len(), 15);
+                    }
+                    LengthType::Count => {
+                        if sub_packets.len() >= 1 << 11 {
+                            return Err(EncodeError::TooMany(sub_packets.len()));
+                        }
+                        writer.write_bits(1, 1);
+                        writer.write_bits(sub_packets.len(), 11);
+                    }
+                }
+                writer.bits.extend(contents.bits);
+            }
         }
 
-        sub_packets
+        Ok(())
     }
 
-    fn value(&self) -> usize {
+    fn value(&self) -> BigUint {
         match self {
-            Packet::Literal { version: _, value } => *value,
-            Packet::Operator {
-                version: _,
-                type_id: 0,
-                sub_packets,
-            } => sub_packets.iter().map(|op| op.value()).sum(),
-            Packet::Operator {
-                version: _,
-                type_id: 1,
-                sub_packets,
-            } => sub_packets.iter().map(|op| op.value()).product(),
-            Packet::Operator {
-                version: _,
-                type_id: 2,
-                sub_packets,
-            } => sub_packets.iter().map(|op| op.value()).min().unwrap(),
+            Packet::Literal { value, .. } => value.clone(),
             Packet::Operator {
-                version: _,
-                type_id: 3,
+                type_id,
                 sub_packets,
-            } => sub_packets.iter().map(|op| op.value()).max().unwrap(),
+                ..
+            } => apply_operator(*type_id, sub_packets.iter().map(Packet::value).collect()),
+        }
+    }
+}
+
+/// An indented listing of the packets in a transmission, one per line, with
+/// the bit offset each starts at and the value it works out to.
+fn disassemble(hex_str: &str) -> Result<String, DecodeError> {
+    let mut lines = vec![];
+    // For each open operator, its line and the values of its operands so far
+    let mut open: Vec<(usize, Vec<BigUint>)> = vec![];
+
+    for event in Decoder::new(hex_str.as_bytes(), Encoding::Hex) {
+        let indent = "  ".repeat(open.len());
+        let value = match event? {
+            Event::Start {
+                offset,
+                version,
+                type_id,
+                length_type,
+                length,
+            } => {
+                let unit = match length_type {
+                    LengthType::Bits => "bits",
+                    LengthType::Count => "packets",
+                };
+                open.push((lines.len(), vec![]));
+                lines.push(format!(
+                    "{:>5}  {}v{} {} [{} {}]",
+                    offset,
+                    indent,
+                    version,
+                    operator(type_id).0,
+                    length,
+                    unit
+                ));
+                continue;
+            }
+            Event::Literal {
+                offset,
+                version,
+                value,
+            } => {
+                lines.push(format!(
+                    "{:>5}  {}v{} literal = {}",
+                    offset, indent, version, value
+                ));
+                value
+            }
+            Event::End { type_id, .. } => {
+                let (line, operands) = open.pop().unwrap();
+                let value = apply_operator(type_id, operands);
+                lines[line] += &format!(" = {}", value);
+                value
+            }
+        };
+
+        if let Some((_, operands)) = open.last_mut() {
+            operands.push(value);
+        }
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// What the operator `type_id` works out to for `operands`, which must
+/// already have been checked against its arity.
+fn apply_operator(type_id: u8, operands: Vec<BigUint>) -> BigUint {
+    let mut operands = operands.into_iter();
+    match type_id {
+        0 => operands.sum(),
+        1 => operands.product(),
+        2 => operands.min().unwrap(),
+        3 => operands.max().unwrap(),
+        5..=7 => {
+            let (a, b) = (operands.next().unwrap(), operands.next().unwrap());
+            let result = match type_id {
+                5 => a > b,
+                6 => a < b,
+                _ => a == b,
+            };
+            BigUint::from(result as u8)
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// The expression as an S-expression, like `(+ 1 (* 2 3))`.
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Packet::Literal { value, .. } => write!(f, "{}", value),
             Packet::Operator {
-                version: _,
-                type_id: 5,
+                type_id,
                 sub_packets,
-            } => (sub_packets[0].value() > sub_packets[1].value()) as usize,
+                ..
+            } => {
+                write!(f, "({}", operator(*type_id).1)?;
+                for packet in sub_packets {
+                    write!(f, " {}", packet)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Packet {
+    /// The expression in infix notation, like `1 + (2 * 3)`, with `min` and
+    /// `max` written as function calls.
+    fn infix(&self) -> String {
+        match self {
+            Packet::Literal { value, .. } => value.to_string(),
             Packet::Operator {
-                version: _,
-                type_id: 6,
+                type_id: 2 | 3,
                 sub_packets,
-            } => (sub_packets[0].value() < sub_packets[1].value()) as usize,
+                ..
+            } => self.infix_operands(sub_packets, ", ", true),
             Packet::Operator {
-                version: _,
-                type_id: 7,
+                type_id,
                 sub_packets,
-            } => (sub_packets[0].value() == sub_packets[1].value()) as usize,
-            _ => unreachable!(),
+                ..
+            } => {
+                let separator = format!(" {} ", operator(*type_id).1);
+                self.infix_operands(sub_packets, &separator, false)
+            }
+        }
+    }
+
+    fn infix_operands(&self, sub_packets: &[Packet], separator: &str, call: bool) -> String {
+        let operands: Vec<String> = sub_packets
+            .iter()
+            .map(|packet| match packet {
+                Packet::Operator {
+                    type_id: 0 | 1 | 5 | 6 | 7,
+                    ..
+                } if !call => format!("({})", packet.infix()),
+                _ => packet.infix(),
+            })
+            .collect();
+
+        match self {
+            Packet::Operator { type_id, .. } if call => {
+                format!("{}({})", operator(*type_id).1, operands.join(separator))
+            }
+            _ => operands.join(separator),
         }
     }
 }
 
-struct BitBuffer {
-    bytes: Vec<u8>,
-    index: usize,
-    bit: usize,
+/// Where an expression failed to compile, as a byte offset into the source.
+#[derive(Debug, PartialEq)]
+enum CompileError {
+    UnexpectedEnd,
+    /// A token that can't go here, such as a stray `)` or trailing input.
+    Unexpected(usize),
+    UnknownOperator(usize),
+    /// Not an integer, or a version that doesn't fit in 3 bits.
+    BadNumber(usize),
+    /// A comparison without exactly two operands, or another operator
+    /// without any.
+    Arity(usize),
 }
 
-impl BitBuffer {
-    fn from_hex_str(hex_str: &str) -> Self {
-        BitBuffer::from_bytes(
-            hex_str
-                .bytes()
-                .map(|b| if b < b'A' { b - b'0' } else { b - b'A' + 10 })
-                .collect(),
-        )
+/// Compile an S-expression such as `(+ 1 (* 2 3))` into packets. Operators
+/// can be given by name or by symbol, and any packet can be given a version
+/// with a suffix like `(sum@3 1 2@5)`; it is 0 otherwise.
+impl FromStr for Packet {
+    type Err = CompileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = tokenise(s).into_iter().peekable();
+        let packet = compile(&mut tokens)?;
+        match tokens.next() {
+            Some((offset, _)) => Err(CompileError::Unexpected(offset)),
+            None => Ok(packet),
+        }
+    }
+}
+
+/// Parentheses and whitespace separated words, with their byte offsets.
+fn tokenise(s: &str) -> Vec<(usize, &str)> {
+    let mut tokens = vec![];
+    let mut start = None;
+    for (i, c) in s.char_indices() {
+        if c.is_whitespace() || c == '(' || c == ')' {
+            if let Some(start) = start.take() {
+                tokens.push((start, &s[start..i]));
+            }
+            if !c.is_whitespace() {
+                tokens.push((i, &s[i..i + 1]));
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
     }
+    if let Some(start) = start {
+        tokens.push((start, &s[start..]));
+    }
+
+    tokens
+}
 
-    fn from_bytes(bytes: Vec<u8>) -> Self {
+fn compile<'a, I>(tokens: &mut std::iter::Peekable<I>) -> Result<Packet, CompileError>
+where
+    I: Iterator<Item = (usize, &'a str)>,
+{
+    let (offset, token) = tokens.next().ok_or(CompileError::UnexpectedEnd)?;
+    if token == ")" {
+        return Err(CompileError::Unexpected(offset));
+    }
+    if token != "(" {
+        let (value, version) = versioned(offset, token)?;
+        let value = value.parse().map_err(|_| CompileError::BadNumber(offset))?;
+        return Ok(Packet::Literal { version, value });
+    }
+
+    let (offset, token) = tokens.next().ok_or(CompileError::UnexpectedEnd)?;
+    let (name, version) = versioned(offset, token)?;
+    let type_id = OPERATORS
+        .iter()
+        .find(|&&(_, op_name, symbol)| name == op_name || name == symbol)
+        .map(|&(id, _, _)| id)
+        .ok_or(CompileError::UnknownOperator(offset))?;
+
+    let mut sub_packets = vec![];
+    loop {
+        match tokens.peek() {
+            Some(&(_, ")")) => {
+                tokens.next();
+                break;
+            }
+            Some(_) => sub_packets.push(compile(tokens)?),
+            None => return Err(CompileError::UnexpectedEnd),
+        }
+    }
+
+    if !check_arity(type_id, sub_packets.len()) {
+        return Err(CompileError::Arity(offset));
+    }
+
+    Ok(Packet::Operator {
+        version,
+        type_id,
+        sub_packets,
+    })
+}
+
+/// Split a `name@version` token, defaulting to version 0.
+fn versioned(offset: usize, token: &str) -> Result<(&str, u8), CompileError> {
+    match token.split_once('@') {
+        None => Ok((token, 0)),
+        Some((name, version)) => match version.parse() {
+            Ok(version) if version < 8 => Ok((name, version)),
+            _ => Err(CompileError::BadNumber(offset)),
+        },
+    }
+}
+
+/// How the bits of a transmission are stored.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Encoding {
+    /// One hex digit per 4 bits, as in the puzzle. Whitespace is skipped.
+    Hex,
+    /// Raw bytes, 8 bits each.
+    Binary,
+}
+
+/// Reads bits on demand from any `io::Read` through a fixed-size buffer.
+struct BitReader<R: Read> {
+    bytes: io::Bytes<BufReader<R>>,
+    encoding: Encoding,
+    current: u8,
+    /// Bits of `current` not handed out yet.
+    available: u32,
+    position: usize,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(reader: R, encoding: Encoding) -> Self {
         Self {
-            bytes,
-            index: 0,
-            bit: 0,
+            bytes: BufReader::new(reader).bytes(),
+            encoding,
+            current: 0,
+            available: 0,
+            position: 0,
+        }
+    }
+
+    /// Load the next byte or hex digit, returning false at the end.
+    fn fill(&mut self) -> Result<bool, DecodeError> {
+        let offset = self.position;
+        for byte in &mut self.bytes {
+            let byte = byte.map_err(|e| DecodeError::Io {
+                offset,
+                kind: e.kind(),
+            })?;
+
+            match self.encoding {
+                Encoding::Binary => {
+                    self.current = byte;
+                    self.available = 8;
+                }
+                Encoding::Hex if byte.is_ascii_whitespace() => continue,
+                Encoding::Hex => {
+                    let digit = (byte as char).to_digit(16).ok_or(DecodeError::InvalidHex {
+                        offset,
+                        found: byte as char,
+                    })?;
+                    self.current = digit as u8;
+                    self.available = 4;
+                }
+            }
+
+            return Ok(true);
         }
+
+        Ok(false)
     }
 
-    fn read_bits(&mut self, n_bits: usize) -> usize {
+    /// Bits read from the source, most significant first.
+    fn read_bits(&mut self, n_bits: usize) -> Result<usize, DecodeError> {
+        let offset = self.position;
         let mut out: usize = 0;
         for _ in 0..n_bits {
-            let this_byte = self.bytes[self.index];
-            let this_bit = (this_byte >> (3 - self.bit)) & 1;
-            out = (out << 1) | this_bit as usize;
-
-            self.bit += 1;
-            if self.bit == 4 {
-                self.bit = 0;
-                self.index += 1;
+            if self.available == 0 && !self.fill()? {
+                return Err(DecodeError::Truncated { offset });
+            }
+
+            self.available -= 1;
+            out = (out << 1) | ((self.current >> self.available) & 1) as usize;
+            self.position += 1;
+        }
+
+        Ok(out)
+    }
+
+    /// How many bits have been read so far.
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The groups of 4 bits that make up a literal's value, each after a bit
+    /// saying whether there are more.
+    fn read_literal(&mut self) -> Result<BigUint, DecodeError> {
+        let mut value = BigUint::default();
+        loop {
+            let end = self.read_bits(1)? == 0;
+            value <<= 4;
+            value |= BigUint::from(self.read_bits(4)?);
+            if end {
+                break;
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn is_empty(&mut self) -> Result<bool, DecodeError> {
+        Ok(self.available == 0 && !self.fill()?)
+    }
+}
+
+/// Something found while streaming through a transmission. Offsets are where
+/// the packet starts, in bits.
+#[derive(Debug, PartialEq)]
+enum Event {
+    Literal {
+        offset: usize,
+        version: u8,
+        value: BigUint,
+    },
+    /// An operator's header has been read; its sub-packets come next.
+    Start {
+        offset: usize,
+        version: u8,
+        type_id: u8,
+        length_type: LengthType,
+        length: usize,
+    },
+    /// The last sub-packet of the operator starting at `offset` has been read.
+    End { offset: usize, type_id: u8 },
+}
+
+/// An operator that has started but not ended.
+struct Frame {
+    offset: usize,
+    type_id: u8,
+    length_type: LengthType,
+    length: usize,
+    /// Where the sub-packets start.
+    start: usize,
+    count: usize,
+}
+
+/// Decodes a transmission one event at a time, SAX style, keeping nothing
+/// but the operators currently open. Stops after the outermost packet, or
+/// after the first error.
+struct Decoder<R: Read> {
+    bits: BitReader<R>,
+    open: Vec<Frame>,
+    finished: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    fn new(reader: R, encoding: Encoding) -> Self {
+        Self {
+            bits: BitReader::new(reader, encoding),
+            open: vec![],
+            finished: false,
+        }
+    }
+
+    fn step(&mut self) -> Result<Event, DecodeError> {
+        let position = self.bits.position();
+        if let Some(frame) = self.open.last() {
+            let done = match frame.length_type {
+                LengthType::Bits => position >= frame.start + frame.length,
+                LengthType::Count => frame.count == frame.length,
+            };
+
+            if done {
+                let frame = self.open.pop().unwrap();
+                if frame.length_type == LengthType::Bits && position != frame.start + frame.length {
+                    return Err(DecodeError::LengthMismatch {
+                        offset: frame.offset,
+                        expected: frame.length,
+                        actual: position - frame.start,
+                    });
+                }
+
+                if !check_arity(frame.type_id, frame.count) {
+                    return Err(DecodeError::Arity {
+                        offset: frame.offset,
+                        type_id: frame.type_id,
+                        count: frame.count,
+                    });
+                }
+
+                return Ok(Event::End {
+                    offset: frame.offset,
+                    type_id: frame.type_id,
+                });
             }
         }
 
-        out
+        if let Some(parent) = self.open.last_mut() {
+            parent.count += 1;
+        }
+
+        let offset = position;
+        let version = self.bits.read_bits(3)? as u8;
+        let type_id = self.bits.read_bits(3)? as u8;
+
+        if type_id == 4 {
+            return Ok(Event::Literal {
+                offset,
+                version,
+                value: self.bits.read_literal()?,
+            });
+        }
+
+        // Every 3-bit id is in use today, but don't trust that to stay true
+        if !is_operator(type_id) {
+            return Err(DecodeError::BadTypeId { offset, type_id });
+        }
+
+        let (length_type, length) = match self.bits.read_bits(1)? {
+            0 => (LengthType::Bits, self.bits.read_bits(15)?),
+            _ => (LengthType::Count, self.bits.read_bits(11)?),
+        };
+        self.open.push(Frame {
+            offset,
+            type_id,
+            length_type,
+            length,
+            start: self.bits.position(),
+            count: 0,
+        });
+
+        Ok(Event::Start {
+            offset,
+            version,
+            type_id,
+            length_type,
+            length,
+        })
     }
 
-    fn peek_bit(&self) -> bool {
-        let this_byte = self.bytes[self.index];
-        let this_bit = (this_byte >> (3 - self.bit)) & 1;
-        this_bit == 1
+    /// Check that everything after the outermost packet is zeros.
+    fn check_padding(&mut self) -> Result<(), DecodeError> {
+        while !self.bits.is_empty()? {
+            let offset = self.bits.position();
+            if self.bits.read_bits(1)? == 1 {
+                return Err(DecodeError::Padding { offset });
+            }
+        }
+
+        Ok(())
     }
+}
+
+impl<R: Read> Iterator for Decoder<R> {
+    type Item = Result<Event, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
 
-    fn is_empty(&self) -> bool {
-        self.index == self.bytes.len()
+        let event = self.step();
+        self.finished = match &event {
+            Ok(Event::Start { .. }) => false,
+            Ok(_) => self.open.is_empty(),
+            Err(_) => true,
+        };
+
+        Some(event)
+    }
+}
+
+/// The sum of every packet's version, streamed.
+fn version_sum<R: Read>(reader: R, encoding: Encoding) -> Result<usize, DecodeError> {
+    let mut sum = 0;
+    for event in Decoder::new(reader, encoding) {
+        match event? {
+            Event::Literal { version, .. } | Event::Start { version, .. } => {
+                sum += version as usize
+            }
+            Event::End { .. } => {}
+        }
+    }
+
+    Ok(sum)
+}
+
+/// The value of the outermost packet, streamed. Each open operator keeps
+/// only a running result, folding in sub-packets as they end.
+fn evaluate<R: Read>(reader: R, encoding: Encoding) -> Result<BigUint, DecodeError> {
+    let mut open: Vec<(u8, Option<BigUint>)> = vec![];
+    for event in Decoder::new(reader, encoding) {
+        let value = match event? {
+            Event::Start { type_id, .. } => {
+                open.push((type_id, None));
+                continue;
+            }
+            Event::Literal { value, .. } => value,
+            Event::End { .. } => open.pop().unwrap().1.unwrap(),
+        };
+
+        match open.last_mut() {
+            None => return Ok(value),
+            Some((type_id, result)) => {
+                *result = Some(match result.take() {
+                    None => value,
+                    Some(acc) => apply_operator(*type_id, vec![acc, value]),
+                })
+            }
+        }
+    }
+
+    unreachable!("the decoder ends with the outermost packet or an error")
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bits: Vec<bool>,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, value: usize, n_bits: usize) {
+        for i in (0..n_bits).rev() {
+            self.bits.push((value >> i) & 1 == 1);
+        }
+    }
+
+    fn to_hex_str(&self) -> String {
+        let mut bits = self.bits.clone();
+        bits.resize(bits.len().div_ceil(8) * 8, false);
+
+        bits.chunks(4)
+            .map(|nibble| {
+                let digit = nibble.iter().fold(0, |acc, &bit| (acc << 1) | bit as u32);
+                std::char::from_digit(digit, 16)
+                    .unwrap()
+                    .to_ascii_uppercase()
+            })
+            .collect()
     }
 }
 
@@ -208,13 +863,11 @@ mod test {
     #[test]
     fn parse_literal() {
         let in_str = "D2FE28";
-        let mut buffer = BitBuffer::from_hex_str(in_str);
-
-        let packet = Packet::from_buffer(&mut buffer);
+        let packet = Packet::decode(in_str).unwrap();
         assert_eq!(
             packet,
             Packet::Literal {
-                value: 2021,
+                value: BigUint::from(2021u32),
                 version: 6
             }
         )
@@ -223,24 +876,22 @@ mod test {
     #[test]
     fn parse_operator() {
         let in_str = "EE00D40C823060";
-        let mut buffer = BitBuffer::from_hex_str(in_str);
-
-        let packet = Packet::from_buffer(&mut buffer);
+        let packet = Packet::decode(in_str).unwrap();
         let expected = Packet::Operator {
             version: 7,
             type_id: 3,
             sub_packets: vec![
                 Packet::Literal {
                     version: 2,
-                    value: 1,
+                    value: BigUint::from(1u32),
                 },
                 Packet::Literal {
                     version: 4,
-                    value: 2,
+                    value: BigUint::from(2u32),
                 },
                 Packet::Literal {
                     version: 1,
-                    value: 3,
+                    value: BigUint::from(3u32),
                 },
             ],
         };
@@ -272,7 +923,331 @@ mod test {
             ("9C005AC2F8F0", 0),
             ("9C0141080250320F1802104A08", 1),
         ] {
-            assert_eq!(parse2(input), expected);
+            assert_eq!(parse2(input), BigUint::from(expected as u32));
+        }
+    }
+
+    #[test]
+    fn encode() {
+        let literal = Packet::Literal {
+            version: 6,
+            value: BigUint::from(2021u32),
+        };
+        assert_eq!(literal.encode(LengthType::Bits).unwrap(), "D2FE28");
+
+        let zero = Packet::Literal {
+            version: 0,
+            value: BigUint::from(0u32),
+        };
+        assert_eq!(zero.encode(LengthType::Count).unwrap(), "1000");
+
+        for (hex, length_type) in [
+            ("38006F45291200", LengthType::Bits),
+            ("EE00D40C823060", LengthType::Count),
+        ] {
+            assert_eq!(
+                Packet::decode(hex).unwrap().encode(length_type).unwrap(),
+                hex
+            );
+        }
+
+        let packet = Packet::decode(INPUT).unwrap();
+        for length_type in [LengthType::Bits, LengthType::Count] {
+            assert_eq!(
+                Packet::decode(&packet.encode(length_type).unwrap()).unwrap(),
+                packet
+            );
+        }
+
+        let operator = |version, type_id| Packet::Operator {
+            version,
+            type_id,
+            sub_packets: vec![zero.clone()],
+        };
+        assert_eq!(
+            operator(9, 0).encode(LengthType::Bits),
+            Err(EncodeError::Version(9))
+        );
+        assert_eq!(
+            operator(1, 12).encode(LengthType::Bits),
+            Err(EncodeError::TypeId(12))
+        );
+        assert_eq!(
+            operator(1, 4).encode(LengthType::Bits),
+            Err(EncodeError::TypeId(4))
+        );
+
+        let empty = Packet::Operator {
+            version: 0,
+            type_id: 0,
+            sub_packets: vec![],
+        };
+        assert_eq!(
+            empty.encode(LengthType::Bits),
+            Err(EncodeError::Arity {
+                type_id: 0,
+                count: 0
+            })
+        );
+        let comparison = Packet::Operator {
+            version: 0,
+            type_id: 5,
+            sub_packets: vec![zero.clone(); 3],
+        };
+        assert_eq!(
+            comparison.encode(LengthType::Count),
+            Err(EncodeError::Arity {
+                type_id: 5,
+                count: 3
+            })
+        );
+
+        let wide = Packet::Operator {
+            version: 0,
+            type_id: 0,
+            sub_packets: vec![zero.clone(); 3000],
+        };
+        assert_eq!(
+            wide.encode(LengthType::Count),
+            Err(EncodeError::TooMany(3000))
+        );
+        assert_eq!(
+            wide.encode(LengthType::Bits).unwrap_err(),
+            EncodeError::TooLong(3000 * 11)
+        );
+    }
+
+    #[test]
+    fn disassemble() {
+        assert_eq!(
+            super::disassemble("38006F45291200").unwrap(),
+            "    0  v1 lt [27 bits] = 1
+   22    v6 literal = 10
+   33    v2 literal = 20"
+        );
+        assert_eq!(
+            super::disassemble("9C0141080250320F1802104A08").unwrap(),
+            "    0  v4 eq [80 bits] = 1
+   22    v2 sum [2 packets] = 4
+   40      v2 literal = 1
+   51      v4 literal = 3
+   62    v6 product [2 packets] = 4
+   80      v0 literal = 2
+   91      v2 literal = 2"
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        let packet = Packet::decode("9C0141080250320F1802104A08").unwrap();
+        assert_eq!(packet.to_string(), "(== (+ 1 3) (* 2 2))");
+        assert_eq!(packet.infix(), "(1 + 3) == (2 * 2)");
+
+        let packet = Packet::decode("880086C3E88112").unwrap();
+        assert_eq!(packet.to_string(), "(min 7 8 9)");
+        assert_eq!(packet.infix(), "min(7, 8, 9)");
+    }
+
+    #[test]
+    fn compile() {
+        let packet: Packet = "(+ 1 (* 2 3))".parse().unwrap();
+        assert_eq!(packet.value(), BigUint::from(7u32));
+        assert_eq!(packet.to_string(), "(+ 1 (* 2 3))");
+
+        let packet: Packet = "(eq@4 (sum@2 1@2 3@4) (product@6 2 2@2))".parse().unwrap();
+        assert_eq!(
+            packet,
+            Packet::decode("9C0141080250320F1802104A08").unwrap()
+        );
+
+        for (source, expected) in [
+            ("(sum 1 2)", 3),
+            ("(product 6 9)", 54),
+            ("(min 7 8 9)", 7),
+            ("(max 7 8 9)", 9),
+            ("(< 5 15)", 1),
+            ("(> 5 15)", 0),
+            ("(== 5 15)", 0),
+            ("(== (+ 1 3) (* 2 2))", 1),
+        ] {
+            let packet: Packet = source.parse().unwrap();
+            for length_type in [LengthType::Bits, LengthType::Count] {
+                assert_eq!(
+                    parse2(&packet.encode(length_type).unwrap()),
+                    BigUint::from(expected as u32)
+                );
+            }
         }
+
+        let error = |source: &str| source.parse::<Packet>().unwrap_err();
+        assert_eq!(error("(+ 1 2"), CompileError::UnexpectedEnd);
+        assert_eq!(error("(+ 1 2))"), CompileError::Unexpected(7));
+        assert_eq!(error("(pow 2 3)"), CompileError::UnknownOperator(1));
+        assert_eq!(error("(+ 1 x)"), CompileError::BadNumber(5));
+        assert_eq!(error("(+@8 1)"), CompileError::BadNumber(1));
+        assert_eq!(error("(< 1 2 3)"), CompileError::Arity(1));
+        assert_eq!(error("(max)"), CompileError::Arity(1));
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(
+            Packet::decode("d2fe28\n"),
+            Ok(Packet::Literal {
+                version: 6,
+                value: BigUint::from(2021u32)
+            })
+        );
+        assert_eq!(
+            Packet::decode("D2FG28"),
+            Err(DecodeError::InvalidHex {
+                offset: 12,
+                found: 'G'
+            })
+        );
+        assert_eq!(
+            Packet::decode("D2FE"),
+            Err(DecodeError::Truncated { offset: 16 })
+        );
+        assert_eq!(
+            Packet::decode("38006F452912"),
+            Err(DecodeError::Truncated { offset: 45 })
+        );
+
+        // An lt of the literals 1, 2 and 3, then the example lt claiming only
+        // 23 bits
+        assert_eq!(
+            Packet::decode("1A00C408821060"),
+            Err(DecodeError::Arity {
+                offset: 0,
+                type_id: 6,
+                count: 3
+            })
+        );
+        assert_eq!(
+            Packet::decode("38005F45291200"),
+            Err(DecodeError::LengthMismatch {
+                offset: 0,
+                expected: 23,
+                actual: 27
+            })
+        );
+
+        assert!(Packet::decode_with("D2FE28", Padding::Strict).is_ok());
+        assert_eq!(Packet::decode("D2FE29"), Packet::decode("D2FE28"));
+        assert_eq!(
+            Packet::decode_with("D2FE29", Padding::Strict),
+            Err(DecodeError::Padding { offset: 23 })
+        );
+    }
+
+    #[test]
+    fn big_values() {
+        // 2^64 + 1 doesn't fit in 16 nibbles, and the product needs 128 bits
+        let big = "18446744073709551617";
+        let packet: Packet = format!("(* {0} (+ {0} 1))", big).parse().unwrap();
+        let expected: BigUint = "340282366920938463518714839652896866306".parse().unwrap();
+        assert_eq!(packet.value(), expected);
+
+        for length_type in [LengthType::Bits, LengthType::Count] {
+            let hex = packet.encode(length_type).unwrap();
+            assert_eq!(Packet::decode(&hex).unwrap(), packet);
+            assert_eq!(parse2(&hex), expected);
+        }
+
+        let packet: Packet = format!("(> {} 18446744073709551615)", big).parse().unwrap();
+        assert_eq!(packet.value(), BigUint::from(1u32));
+    }
+
+    fn to_bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn stream() {
+        let literal = |offset, version, value: u32| Event::Literal {
+            offset,
+            version,
+            value: BigUint::from(value),
+        };
+        let events: Vec<Event> = Decoder::new("38006F45291200".as_bytes(), Encoding::Hex)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                Event::Start {
+                    offset: 0,
+                    version: 1,
+                    type_id: 6,
+                    length_type: LengthType::Bits,
+                    length: 27
+                },
+                literal(22, 6, 10),
+                literal(33, 2, 20),
+                Event::End {
+                    offset: 0,
+                    type_id: 6
+                },
+            ]
+        );
+
+        let binary = to_bytes(INPUT);
+        assert_eq!(version_sum(&binary[..], Encoding::Binary), Ok(875));
+        assert_eq!(
+            evaluate(&binary[..], Encoding::Binary),
+            Ok(BigUint::from(1264857437203u64))
+        );
+
+        assert_eq!(
+            evaluate("38006F452912".as_bytes(), Encoding::Hex),
+            Err(DecodeError::Truncated { offset: 45 })
+        );
+        assert_eq!(
+            version_sum("8A004A80\n1A8002F47x".as_bytes(), Encoding::Hex),
+            Err(DecodeError::InvalidHex {
+                offset: 68,
+                found: 'x'
+            })
+        );
+    }
+
+    #[test]
+    fn large_stream() {
+        // A product of sums, each of a thousand literals, is megabytes of hex
+        let sum = |i: usize| Packet::Operator {
+            version: 1,
+            type_id: 0,
+            sub_packets: (0..1000)
+                .map(|j| Packet::Literal {
+                    version: 2,
+                    value: BigUint::from((i * 1000 + j) as u64),
+                })
+                .collect(),
+        };
+        let packet = Packet::Operator {
+            version: 3,
+            type_id: 1,
+            sub_packets: (1..=250).map(sum).collect(),
+        };
+        let hex = packet.encode(LengthType::Count).unwrap();
+        assert!(hex.len() > 1 << 20);
+
+        let expected = packet.value();
+        assert_eq!(
+            evaluate(hex.as_bytes(), Encoding::Hex),
+            Ok(expected.clone())
+        );
+        assert_eq!(
+            evaluate(&to_bytes(&hex)[..], Encoding::Binary),
+            Ok(expected)
+        );
+        assert_eq!(
+            version_sum(hex.as_bytes(), Encoding::Hex),
+            Ok(3 + 250 + 250 * 1000 * 2)
+        );
     }
 }
@@ -1,3 +1,8 @@
+#![allow(dead_code)]
+
+use std::num::ParseIntError;
+use std::str::FromStr;
+
 const INPUT: &'static str = include_str!("../inputs/day7.txt");
 
 pub(crate) fn run() {
@@ -8,57 +13,235 @@ pub(crate) fn run() {
     println!("day 7, output 2: {}", res);
 }
 
-fn parse1(input: &str) -> i32 {
-    let mut min = i32::MAX;
-    let mut max = i32::MIN;
+fn parse1(input: &str) -> i128 {
+    align(&parse_crabs(input), 1, Cost::Linear).1
+}
+
+fn parse2(input: &str) -> i128 {
+    align(&parse_crabs(input), 1, Cost::Triangular).1
+}
 
-    let pos: Vec<i32> = input
+fn parse_crabs(input: &str) -> Vec<Crab> {
+    input
+        .trim()
         .split(',')
-        .map(|x| {
-            let val = x.parse().unwrap();
-            min = min.min(val);
-            max = max.max(val);
+        .map(|x| x.parse().unwrap())
+        .collect()
+}
+
+/// A crab at `pos` whose fuel use is scaled by `weight`. Parsed from either
+/// `pos` or `pos:weight`, where the weight must be at least 1.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Crab {
+    pos: i64,
+    weight: i64,
+}
 
-            val
-        })
-        .collect();
+#[derive(Debug, PartialEq)]
+enum ParseCrabError {
+    Int(ParseIntError),
+    /// Zero or negative weights break the median and mean targets.
+    Weight(i64),
+}
 
-    let mut gas = i32::MAX;
-    for target in min..=max {
-        gas = gas.min(pos.iter().map(|x| (target - x).abs()).sum());
+impl From<ParseIntError> for ParseCrabError {
+    fn from(e: ParseIntError) -> Self {
+        ParseCrabError::Int(e)
     }
+}
+
+impl FromStr for Crab {
+    type Err = ParseCrabError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pos, weight) = match s.split_once(':') {
+            Some((pos, weight)) => (pos.parse()?, weight.parse()?),
+            None => (s.parse()?, 1),
+        };
 
-    gas
+        if weight < 1 {
+            return Err(ParseCrabError::Weight(weight));
+        }
+
+        Ok(Self { pos, weight })
+    }
 }
 
-fn parse2(input: &str) -> i32 {
-    let mut min = i32::MAX;
-    let mut max = i32::MIN;
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Cost {
+    Linear,
+    Triangular,
+}
 
-    let pos: Vec<i32> = input
-        .split(',')
-        .map(|x| {
-            let val = x.parse().unwrap();
-            min = min.min(val);
-            max = max.max(val);
+impl Cost {
+    fn per_crab(&self, d: i64) -> i128 {
+        let d = d.abs() as i128;
+        match self {
+            Cost::Linear => d,
+            Cost::Triangular => d * (d + 1) / 2,
+        }
+    }
 
-            val
-        })
-        .collect();
+    /// Cost of moving the sorted `crabs[lo..hi]` to `target`.
+    fn segment(&self, sums: &PrefixSums, lo: usize, hi: usize, target: i64) -> i128 {
+        let linear = sums.linear(lo, hi, target);
+        match self {
+            Cost::Linear => linear,
+            Cost::Triangular => (sums.squared(lo, hi, target) + linear) / 2,
+        }
+    }
 
-    let mut gas = i32::MAX;
-    for target in min..=max {
-        let cost = pos
-            .iter()
-            .map(|x| {
-                let d = (target - x).abs();
-                d * (d + 1) / 2
-            })
-            .sum();
-        gas = gas.min(cost);
+    /// Best single target for the sorted `crabs[lo..hi]`. For the linear cost
+    /// this is the weighted median. The triangular optimum is always within
+    /// 1/2 of the weighted mean, so only the integers around it are checked.
+    fn best_target(&self, sums: &PrefixSums, lo: usize, hi: usize) -> (i64, i128) {
+        match self {
+            Cost::Linear => {
+                let target = sums.weighted_median(lo, hi);
+                (target, self.segment(sums, lo, hi, target))
+            }
+            Cost::Triangular => {
+                let mean = sums.wp(lo, hi).div_euclid(sums.w(lo, hi)) as i64;
+                (mean - 1..=mean + 2)
+                    .map(|target| (target, self.segment(sums, lo, hi, target)))
+                    .min_by_key(|&(_, fuel)| fuel)
+                    .unwrap()
+            }
+        }
     }
+}
 
-    gas
+/// Prefix sums of `w`, `w*p` and `w*p^2` over crabs sorted by position, so
+/// the fuel for any contiguous run of crabs can be found in O(log n).
+struct PrefixSums {
+    pos: Vec<i64>,
+    w: Vec<i128>,
+    wp: Vec<i128>,
+    wpp: Vec<i128>,
+}
+
+impl PrefixSums {
+    fn new(crabs: &[Crab]) -> Self {
+        let mut sums = Self {
+            pos: crabs.iter().map(|c| c.pos).collect(),
+            w: vec![0],
+            wp: vec![0],
+            wpp: vec![0],
+        };
+
+        for crab in crabs {
+            let (w, p) = (crab.weight as i128, crab.pos as i128);
+            sums.w.push(sums.w.last().unwrap() + w);
+            sums.wp.push(sums.wp.last().unwrap() + w * p);
+            sums.wpp.push(sums.wpp.last().unwrap() + w * p * p);
+        }
+
+        sums
+    }
+
+    fn w(&self, lo: usize, hi: usize) -> i128 {
+        self.w[hi] - self.w[lo]
+    }
+
+    fn wp(&self, lo: usize, hi: usize) -> i128 {
+        self.wp[hi] - self.wp[lo]
+    }
+
+    fn wpp(&self, lo: usize, hi: usize) -> i128 {
+        self.wpp[hi] - self.wpp[lo]
+    }
+
+    fn weighted_median(&self, lo: usize, hi: usize) -> i64 {
+        let total = self.w(lo, hi);
+        let mid = lo + self.w[lo + 1..=hi].partition_point(|&w| 2 * (w - self.w[lo]) < total);
+        self.pos[mid]
+    }
+
+    /// Sum of `w * |t - p|`.
+    fn linear(&self, lo: usize, hi: usize, t: i64) -> i128 {
+        let split = lo + self.pos[lo..hi].partition_point(|&p| p <= t);
+        let t = t as i128;
+
+        let left = t * self.w(lo, split) - self.wp(lo, split);
+        let right = self.wp(split, hi) - t * self.w(split, hi);
+
+        left + right
+    }
+
+    /// Sum of `w * (t - p)^2`.
+    fn squared(&self, lo: usize, hi: usize, t: i64) -> i128 {
+        let t = t as i128;
+        t * t * self.w(lo, hi) - 2 * t * self.wp(lo, hi) + self.wpp(lo, hi)
+    }
+}
+
+/// Choose `k` alignment positions minimising the total fuel when every crab
+/// moves to its nearest position. Each position serves a contiguous run of
+/// the sorted crabs, so this is a DP over where each run ends.
+fn align(crabs: &[Crab], k: usize, cost: Cost) -> (Vec<i64>, i128) {
+    let mut crabs = crabs.to_vec();
+    crabs.sort_by_key(|c| c.pos);
+
+    let n = crabs.len();
+    let k = k.min(n);
+    let sums = PrefixSums::new(&crabs);
+
+    // best[m][i]: cheapest way to serve the first i crabs with m positions,
+    // along with the start of the last run and its target.
+    let mut best = vec![vec![(i128::MAX, 0, 0); n + 1]; k + 1];
+    best[0][0] = (0, 0, 0);
+
+    for m in 1..=k {
+        // Only the full set of crabs matters once the last position is placed
+        let ends = if m == k { n..=n } else { m..=n };
+        for i in ends {
+            for j in m - 1..i {
+                let (prev, _, _) = best[m - 1][j];
+                if prev == i128::MAX {
+                    continue;
+                }
+
+                let (target, fuel) = cost.best_target(&sums, j, i);
+                if prev + fuel < best[m][i].0 {
+                    best[m][i] = (prev + fuel, j, target);
+                }
+            }
+        }
+    }
+
+    let mut targets = Vec::with_capacity(k);
+    let mut i = n;
+    for m in (1..=k).rev() {
+        let (_, j, target) = best[m][i];
+        targets.push(target);
+        i = j;
+    }
+    targets.reverse();
+
+    (targets, best[k][n].0)
+}
+
+fn fuel<F: Fn(i64) -> i128>(pos: &[i64], target: i64, cost: F) -> i128 {
+    pos.iter().map(|&x| cost(target - x)).sum()
+}
+
+/// Find the target minimising the total fuel for any convex per-crab `cost`.
+/// The total is then convex in the target too, so we binary search for the
+/// first target where moving one step further right stops getting cheaper.
+fn min_fuel<F: Fn(i64) -> i128>(pos: &[i64], cost: F) -> (i64, i128) {
+    let mut lo = *pos.iter().min().unwrap();
+    let mut hi = *pos.iter().max().unwrap();
+
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if fuel(pos, mid + 1, &cost) >= fuel(pos, mid, &cost) {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+
+    (lo, fuel(pos, lo, &cost))
 }
 
 #[cfg(test)]
@@ -67,6 +250,10 @@ mod test {
 
     const INPUTS: &'static str = "16,1,2,0,4,2,7,1,2,14";
 
+    fn positions(input: &str) -> Vec<i64> {
+        parse_crabs(input).iter().map(|c| c.pos).collect()
+    }
+
     #[test]
     fn first() {
         assert_eq!(parse1(INPUTS), 37);
@@ -76,4 +263,61 @@ mod test {
     fn second() {
         assert_eq!(parse2(INPUTS), 168);
     }
+
+    #[test]
+    fn convex_search() {
+        let pos = positions(INPUTS);
+        assert_eq!(min_fuel(&pos, |d| Cost::Linear.per_crab(d)), (2, 37));
+        assert_eq!(min_fuel(&pos, |d| Cost::Triangular.per_crab(d)), (5, 168));
+        assert_eq!(min_fuel(&pos, |d| (d as i128).pow(2)), (5, 291));
+    }
+
+    #[test]
+    fn large_positions() {
+        let input = "-1000000000,1000000000,999999999,-999999999,0";
+        let pos = positions(input);
+        assert_eq!(
+            parse1(input),
+            min_fuel(&pos, |d| Cost::Linear.per_crab(d)).1
+        );
+        assert_eq!(
+            parse2(input),
+            min_fuel(&pos, |d| Cost::Triangular.per_crab(d)).1
+        );
+        assert_eq!(parse1(input), 3_999_999_998);
+    }
+
+    #[test]
+    fn weighted() {
+        let crabs = parse_crabs("0:1,10:3");
+        assert_eq!(align(&crabs, 1, Cost::Linear), (vec![10], 10));
+        assert_eq!(align(&crabs, 1, Cost::Triangular), (vec![8], 45));
+
+        assert_eq!("0:0".parse::<Crab>(), Err(ParseCrabError::Weight(0)));
+        assert_eq!("5:-3".parse::<Crab>(), Err(ParseCrabError::Weight(-3)));
+        assert!(matches!("x:1".parse::<Crab>(), Err(ParseCrabError::Int(_))));
+    }
+
+    #[test]
+    fn multi_target() {
+        let crabs = parse_crabs(INPUTS);
+        assert_eq!(align(&crabs, 2, Cost::Linear), (vec![2, 14], 13));
+        assert_eq!(align(&crabs, 2, Cost::Triangular), (vec![2, 15], 25));
+        assert_eq!(align(&crabs, 10, Cost::Linear).1, 0);
+
+        // Brute force every pair of targets against the DP
+        for cost in [Cost::Linear, Cost::Triangular] {
+            let mut best = i128::MAX;
+            for a in 0..=16 {
+                for b in a..=16 {
+                    let total = crabs
+                        .iter()
+                        .map(|c| cost.per_crab(c.pos - a).min(cost.per_crab(c.pos - b)))
+                        .sum();
+                    best = best.min(total);
+                }
+            }
+            assert_eq!(align(&crabs, 2, cost).1, best);
+        }
+    }
 }
@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+#![allow(dead_code)]
+
 use std::str::FromStr;
 
 const INPUT: &'static str = include_str!("../inputs/day8.txt");
@@ -28,118 +29,285 @@ fn parse1(input: &str) -> usize {
 }
 
 fn parse2(input: &str) -> usize {
-    let mut res = 0;
-
-    input.lines().for_each(|line| {
-        let (sig, out) = line.split_once(" | ").unwrap();
-        let mut signal_patterns: Vec<Signal> =
-            sig.split_whitespace().map(|s| s.parse().unwrap()).collect();
-
-        let mut map: HashMap<Signal, usize> = HashMap::new();
-        let mut encodings: Vec<Option<Signal>> = vec![None; 10];
-        let mut remainder = vec![];
-        for sig in signal_patterns {
-            match sig.len() {
-                2 => {
-                    map.insert(sig, 1);
-                    encodings[1] = Some(sig);
-                }
-                3 => {
-                    map.insert(sig, 7);
-                    encodings[7] = Some(sig);
-                }
-                4 => {
-                    map.insert(sig, 4);
-                    encodings[4] = Some(sig);
-                }
-                7 => {
-                    map.insert(sig, 8);
-                    encodings[8] = Some(sig);
-                }
-                _ => remainder.push(sig),
-            }
+    let display = Display::seven_segment();
+
+    input
+        .lines()
+        .map(|line| {
+            let (patterns, output) = parse_entry(line);
+            display
+                .decode_entry(&patterns, &output)
+                .unwrap()
+                .parse::<usize>()
+                .unwrap()
+        })
+        .sum()
+}
+
+fn parse_entry(line: &str) -> (Vec<Signal>, Vec<Signal>) {
+    let (sig, out) = line.split_once(" | ").unwrap();
+    let parse = |s: &str| s.split_whitespace().map(|s| s.parse().unwrap()).collect();
+
+    (parse(sig), parse(out))
+}
+
+#[derive(Debug, PartialEq)]
+enum DecodeError {
+    /// No wiring maps every pattern onto a symbol of the display.
+    Unsatisfiable,
+    /// More than one wiring fits the observed patterns.
+    Ambiguous,
+    /// An output pattern doesn't match any symbol under the solved wiring.
+    UnknownPattern(Signal),
+}
+
+/// Maps each wire to the display segment it actually drives.
+#[derive(Debug, PartialEq, Clone)]
+struct Wiring(Vec<usize>);
+
+impl Wiring {
+    fn apply(&self, sig: Signal) -> Signal {
+        let segments = self
+            .0
+            .iter()
+            .enumerate()
+            .filter(|&(wire, _)| sig.0 >> wire & 1 == 1)
+            .fold(0, |acc, (_, &seg)| acc | 1 << seg);
+
+        Signal(segments)
+    }
+}
+
+/// A display definition: the segments lit for each symbol it can show.
+struct Display {
+    symbols: Vec<(char, Signal)>,
+    n_segments: usize,
+}
+
+impl Display {
+    fn new(symbols: &[(char, &str)]) -> Self {
+        let symbols: Vec<(char, Signal)> = symbols
+            .iter()
+            .map(|&(c, segments)| (c, segments.parse().unwrap()))
+            .collect();
+        let all = symbols.iter().fold(0, |acc, (_, sig)| acc | sig.0);
+
+        Self {
+            symbols,
+            n_segments: (usize::BITS - all.leading_zeros()) as usize,
         }
+    }
 
-        signal_patterns = remainder;
-        let mut remainder = vec![];
-        for sig in signal_patterns {
-            match sig.len() {
-                6 => {
-                    if sig.is_superset(&encodings[4].unwrap()) {
-                        map.insert(sig, 9);
-                        encodings[9] = Some(sig);
-                    } else if sig.is_superset(&encodings[1].unwrap()) {
-                        map.insert(sig, 0);
-                        encodings[0] = Some(sig);
-                    } else {
-                        map.insert(sig, 6);
-                        encodings[6] = Some(sig);
-                    }
-                }
-                _ => remainder.push(sig),
+    fn seven_segment() -> Self {
+        Self::new(&[
+            ('0', "abcefg"),
+            ('1', "cf"),
+            ('2', "acdeg"),
+            ('3', "acdfg"),
+            ('4', "bcdf"),
+            ('5', "abdfg"),
+            ('6', "abdefg"),
+            ('7', "acf"),
+            ('8', "abcdefg"),
+            ('9', "abcdfg"),
+        ])
+    }
+
+    fn symbol(&self, segments: Signal) -> Option<char> {
+        self.symbols
+            .iter()
+            .find(|&&(_, sig)| sig == segments)
+            .map(|&(c, _)| c)
+    }
+
+    /// The symbol nearest to `segments` by Hamming distance, provided it's
+    /// the only one at that distance.
+    fn nearest(&self, segments: Signal) -> Option<(char, usize)> {
+        let distance = |sym: &Signal| (sym.0 ^ segments.0).count_ones() as usize;
+        let min = self.symbols.iter().map(|(_, sym)| distance(sym)).min()?;
+
+        let mut nearest = self.symbols.iter().filter(|(_, sym)| distance(sym) == min);
+        match (nearest.next(), nearest.next()) {
+            (Some(&(c, _)), None) => Some((c, min)),
+            _ => None,
+        }
+    }
+
+    /// Find the unique wiring under which every observed pattern is one of the
+    /// display's symbols, backtracking over wire assignments.
+    fn solve(&self, patterns: &[Signal]) -> Result<Wiring, DecodeError> {
+        self.best_wiring(patterns, 0)
+    }
+
+    /// Find the unique wiring that needs the fewest segment flips to turn the
+    /// patterns into symbols, with at most `max_faults` patterns flipped.
+    fn best_wiring(&self, patterns: &[Signal], max_faults: usize) -> Result<Wiring, DecodeError> {
+        if patterns.iter().any(|p| p.0 >> self.n_segments != 0) {
+            return Err(DecodeError::Unsatisfiable);
+        }
+
+        let mut best = (usize::MAX, vec![]);
+        let mut wiring = vec![0; self.n_segments];
+        self.search(patterns, max_faults, &mut wiring, 0, 0, &mut best);
+
+        let (_, mut solutions) = best;
+        match solutions.len() {
+            0 => Err(DecodeError::Unsatisfiable),
+            1 => Ok(solutions.pop().unwrap()),
+            _ => Err(DecodeError::Ambiguous),
+        }
+    }
+
+    fn search(
+        &self,
+        patterns: &[Signal],
+        max_faults: usize,
+        wiring: &mut [usize],
+        wire: usize,
+        used: usize,
+        best: &mut (usize, Vec<Wiring>),
+    ) {
+        if wire == self.n_segments {
+            let (cost, _) = self.lower_bound(patterns, wiring);
+            if cost < best.0 {
+                *best = (cost, vec![]);
             }
+            best.1.push(Wiring(wiring.to_vec()));
+            return;
         }
 
-        signal_patterns = remainder;
-        for sig in signal_patterns {
-            if sig.is_superset(&encodings[1].unwrap()) {
-                map.insert(sig, 3);
-            } else if sig.is_subset(&encodings[9].unwrap()) {
-                map.insert(sig, 5);
-            } else {
-                map.insert(sig, 2);
+        for seg in 0..self.n_segments {
+            if used >> seg & 1 == 1 {
+                continue;
+            }
+
+            wiring[wire] = seg;
+            let (cost, faults) = self.lower_bound(patterns, &wiring[..=wire]);
+
+            // Two solutions at the best cost are enough to know it's ambiguous
+            let beaten = cost > best.0 || (cost == best.0 && best.1.len() > 1);
+            if faults <= max_faults && !beaten {
+                self.search(
+                    patterns,
+                    max_faults,
+                    wiring,
+                    wire + 1,
+                    used | 1 << seg,
+                    best,
+                );
             }
         }
+    }
 
-        res += out
-            .split_whitespace()
-            .map(|s| {
-                let sig: Signal = s.parse().unwrap();
-                map.get(&sig).unwrap()
-            })
-            .fold(0, |acc, x| acc * 10 + x);
-    });
+    /// Lower bounds on the total segment flips, and on the number of patterns
+    /// needing any flips, given the wires assigned so far. A pattern's image
+    /// can't be closer to a symbol than their difference in size, nor than
+    /// the assigned wires that already disagree with it.
+    fn lower_bound(&self, patterns: &[Signal], partial: &[usize]) -> (usize, usize) {
+        patterns.iter().fold((0, 0), |(cost, faults), p| {
+            let (mut lit, mut unlit) = (0, 0);
+            for (wire, &seg) in partial.iter().enumerate() {
+                if p.0 >> wire & 1 == 1 {
+                    lit |= 1 << seg;
+                } else {
+                    unlit |= 1 << seg;
+                }
+            }
 
-    res
-}
+            let flips = self
+                .symbols
+                .iter()
+                .map(|(_, sym)| {
+                    let size = (sym.len() as isize - p.len() as isize).unsigned_abs();
+                    let wrong = (lit & !sym.0 | unlit & sym.0).count_ones() as usize;
+                    size.max(wrong)
+                })
+                .min()
+                .unwrap();
 
-#[derive(Eq, Hash, Clone, Copy)]
-struct Signal(usize);
+            (cost + flips, faults + (flips > 0) as usize)
+        })
+    }
 
-impl Signal {
-    fn len(&self) -> usize {
-        let mut n_set = 0;
-        for i in 0..7 {
-            n_set += (self.0 >> i) & 1;
-        }
-        n_set
+    fn decode(&self, wiring: &Wiring, sig: Signal) -> Result<char, DecodeError> {
+        self.symbol(wiring.apply(sig))
+            .ok_or(DecodeError::UnknownPattern(sig))
     }
 
-    fn is_subset(&self, other: &Self) -> bool {
-        (self.0 ^ other.0) & self.0 == 0
+    fn decode_entry(&self, patterns: &[Signal], output: &[Signal]) -> Result<String, DecodeError> {
+        let wiring = self.solve(patterns)?;
+        output
+            .iter()
+            .map(|&sig| self.decode(&wiring, sig))
+            .collect()
     }
 
-    fn is_superset(&self, other: &Self) -> bool {
-        other.is_subset(self)
+    /// Decode an entry where up to `max_faults` of the patterns may have
+    /// dropped or gained segments. Patterns and output digits that don't
+    /// exactly match a symbol are decoded as their nearest symbol and flagged.
+    fn recover_entry(
+        &self,
+        patterns: &[Signal],
+        output: &[Signal],
+        max_faults: usize,
+    ) -> Result<Recovery, DecodeError> {
+        let wiring = self.best_wiring(patterns, max_faults)?;
+        let repaired = |signals: &[Signal]| -> Vec<usize> {
+            (0..signals.len())
+                .filter(|&i| self.symbol(wiring.apply(signals[i])).is_none())
+                .collect()
+        };
+
+        let repaired_patterns = repaired(patterns);
+        let repaired_output = repaired(output);
+        let output = output
+            .iter()
+            .map(|&sig| {
+                self.nearest(wiring.apply(sig))
+                    .map(|(c, _)| c)
+                    .ok_or(DecodeError::UnknownPattern(sig))
+            })
+            .collect::<Result<String, DecodeError>>()?;
+
+        Ok(Recovery {
+            wiring,
+            output,
+            repaired_patterns,
+            repaired_output,
+        })
     }
 }
 
-impl FromStr for Signal {
-    type Err = std::io::Error;
+/// A decoded entry along with the indices of the patterns and output digits
+/// that had to be repaired to get there.
+#[derive(Debug, PartialEq)]
+struct Recovery {
+    wiring: Wiring,
+    output: String,
+    repaired_patterns: Vec<usize>,
+    repaired_output: Vec<usize>,
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let encoding = s
-            .chars()
-            .map(|c| c.to_digit(26).unwrap() as usize - 10)
-            .rfold(0, |acc, offset| acc | (1 << offset));
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+struct Signal(usize);
 
-        Ok(Self(encoding))
+impl Signal {
+    fn len(&self) -> usize {
+        self.0.count_ones() as usize
     }
 }
 
-impl PartialEq for Signal {
-    fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
+impl FromStr for Signal {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.chars().try_fold(Self(0), |acc, c| match c {
+            'a'..='z' => Ok(Self(acc.0 | 1 << (c as u8 - b'a'))),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid segment '{}'", c),
+            )),
+        })
     }
 }
 
@@ -168,4 +336,106 @@ gcafb gcf dcaebfg ecagb gf abcdeg gaef cafbge fdbac fegbdc | fgae cfgab fg bagce
     fn second() {
         assert_eq!(parse2(INPUTS), 61229);
     }
+
+    #[test]
+    fn unsolvable_entries() {
+        let display = Display::seven_segment();
+        let sig = |s: &str| s.parse::<Signal>().unwrap();
+
+        // Only the two-segment "1" has been seen, so c and f can be swapped
+        assert_eq!(display.solve(&[sig("ab")]), Err(DecodeError::Ambiguous));
+
+        // Two different two-segment patterns can't both be a "1"
+        assert_eq!(
+            display.solve(&[sig("ab"), sig("cd")]),
+            Err(DecodeError::Unsatisfiable)
+        );
+
+        let (patterns, _) = parse_entry(INPUTS.lines().next().unwrap());
+        assert_eq!(
+            display.decode_entry(&patterns, &[sig("be"), sig("bcde")]),
+            Err(DecodeError::UnknownPattern(sig("bcde")))
+        );
+    }
+
+    #[test]
+    fn hex_display() {
+        let display = Display::new(&[
+            ('0', "abcefg"),
+            ('1', "cf"),
+            ('2', "acdeg"),
+            ('3', "acdfg"),
+            ('4', "bcdf"),
+            ('5', "abdfg"),
+            ('6', "abdefg"),
+            ('7', "acf"),
+            ('8', "abcdefg"),
+            ('9', "abcdfg"),
+            ('A', "abcdef"),
+            ('b', "bdefg"),
+            ('C', "abeg"),
+            ('d', "cdefg"),
+            ('E', "abdeg"),
+            ('F', "abde"),
+        ]);
+
+        // Scramble the wires with a -> g, b -> f, ... and show every symbol
+        let scramble = |s: &str| -> Signal {
+            s.chars()
+                .map(|c| (b'g' - (c as u8 - b'a')) as char)
+                .collect::<String>()
+                .parse()
+                .unwrap()
+        };
+        let patterns: Vec<Signal> = display
+            .symbols
+            .iter()
+            .map(|(_, sym)| {
+                let segments: String = (0..7)
+                    .filter(|i| sym.0 >> i & 1 == 1)
+                    .map(|i| (b'a' + i as u8) as char)
+                    .collect();
+                scramble(&segments)
+            })
+            .collect();
+        let output: Vec<Signal> = ["abde", "cf", "bdefg", "abcdefg"]
+            .iter()
+            .map(|s| scramble(s))
+            .collect();
+
+        assert_eq!(
+            display.decode_entry(&patterns, &output),
+            Ok("F1b8".to_string())
+        );
+    }
+
+    #[test]
+    fn corrupted_patterns() {
+        let display = Display::seven_segment();
+        let sig = |s: &str| s.parse::<Signal>().unwrap();
+
+        let (mut patterns, output) = parse_entry(INPUTS.lines().next().unwrap());
+        let expected = display.solve(&patterns).unwrap();
+
+        // Drop a segment from one pattern and add one to another
+        patterns[6] = sig("aebfd");
+        patterns[0] = sig("bea");
+        assert_eq!(display.solve(&patterns), Err(DecodeError::Unsatisfiable));
+        assert_eq!(
+            display.recover_entry(&patterns, &output, 1),
+            Err(DecodeError::Unsatisfiable)
+        );
+
+        let recovery = display.recover_entry(&patterns, &output, 2).unwrap();
+        assert_eq!(recovery.wiring, expected);
+        assert_eq!(recovery.output, "8394");
+        assert_eq!(recovery.repaired_patterns, vec![0, 6]);
+        assert!(recovery.repaired_output.is_empty());
+
+        // A glitched output digit is repaired too
+        let glitched = [sig("fdgacbe"), sig("cefdb"), sig("cefbgd"), sig("gce")];
+        let recovery = display.recover_entry(&patterns, &glitched, 2).unwrap();
+        assert_eq!(recovery.output, "8394");
+        assert_eq!(recovery.repaired_output, vec![3]);
+    }
 }
@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+
 use std::collections::VecDeque;
 
 const INPUT: &'static str = include_str!("../inputs/day9.txt");
@@ -51,55 +53,311 @@ fn parse1(input: &str) -> usize {
 }
 
 fn parse2(input: &str) -> usize {
-    let mut grid: Vec<Vec<usize>> = input
+    let grid = parse_grid(input);
+    let segmentation = segment(&grid, 9, Connectivity::Four);
+
+    let mut basin_sizes: Vec<usize> = segmentation.basins.iter().map(|b| b.size).collect();
+    basin_sizes.sort_by(|a, b| b.cmp(a));
+    basin_sizes[0..3].iter().product()
+}
+
+fn parse_grid(input: &str) -> Vec<Vec<usize>> {
+    input
         .lines()
         .map(|line| {
             line.chars()
                 .map(|c| c.to_digit(10).unwrap() as usize)
                 .collect()
         })
-        .collect();
+        .collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Connectivity {
+    Four,
+    Eight,
+}
+
+impl Connectivity {
+    fn offsets(&self) -> &'static [(isize, isize)] {
+        match self {
+            Connectivity::Four => &[(-1, 0), (0, -1), (0, 1), (1, 0)],
+            Connectivity::Eight => &[
+                (-1, -1),
+                (-1, 0),
+                (-1, 1),
+                (0, -1),
+                (0, 1),
+                (1, -1),
+                (1, 0),
+                (1, 1),
+            ],
+        }
+    }
+
+    /// Neighbours that come earlier in row-major order, which is all a single
+    /// scan needs to join every adjacent pair of cells.
+    fn preceding(&self) -> &'static [(isize, isize)] {
+        match self {
+            Connectivity::Four => &[(-1, 0), (0, -1)],
+            Connectivity::Eight => &[(-1, -1), (-1, 0), (-1, 1), (0, -1)],
+        }
+    }
+}
+
+struct UnionFind {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(n: usize) -> Self {
+        Self {
+            parent: (0..n).collect(),
+            size: vec![1; n],
+        }
+    }
+
+    fn find(&mut self, mut x: usize) -> usize {
+        while self.parent[x] != x {
+            self.parent[x] = self.parent[self.parent[x]];
+            x = self.parent[x];
+        }
+        x
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (mut a, mut b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+
+        if self.size[a] < self.size[b] {
+            std::mem::swap(&mut a, &mut b);
+        }
+        self.parent[b] = a;
+        self.size[a] += self.size[b];
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Basin {
+    size: usize,
+    low_point: (usize, usize),
+    /// Top left and bottom right corners, inclusive.
+    bounds: ((usize, usize), (usize, usize)),
+}
+
+#[derive(Debug)]
+struct Segmentation {
+    /// Index into `basins` for each cell, or `None` for ridge cells.
+    labels: Vec<Vec<Option<usize>>>,
+    basins: Vec<Basin>,
+    ridges: Vec<(usize, usize)>,
+}
 
+/// Split the heightmap into basins separated by cells at or above `barrier`.
+/// Basins are labelled in the order their first cell appears, scanning rows
+/// top to bottom.
+fn segment(grid: &[Vec<usize>], barrier: usize, connectivity: Connectivity) -> Segmentation {
     let n_rows = grid.len();
     let n_cols = grid[0].len();
+    let index = |row: usize, col: usize| row * n_cols + col;
+
+    let mut sets = UnionFind::new(n_rows * n_cols);
+    for row in 0..n_rows {
+        for col in 0..n_cols {
+            if grid[row][col] >= barrier {
+                continue;
+            }
+
+            for (dr, dc) in connectivity.preceding() {
+                let (r, c) = (row as isize + dr, col as isize + dc);
+                if r < 0 || c < 0 || c >= n_cols as isize {
+                    continue;
+                }
+
+                let (r, c) = (r as usize, c as usize);
+                if grid[r][c] < barrier {
+                    sets.union(index(row, col), index(r, c));
+                }
+            }
+        }
+    }
 
-    let mut basin_sizes: Vec<usize> = vec![];
+    let mut labels = vec![vec![None; n_cols]; n_rows];
+    let mut root_labels = vec![None; n_rows * n_cols];
+    let mut basins: Vec<Basin> = vec![];
+    let mut ridges = vec![];
 
     for row in 0..n_rows {
         for col in 0..n_cols {
-            if grid[row][col] != 9 {
-                let mut basin_size = 0;
-                let mut queue = VecDeque::from(vec![(row, col)]);
-                grid[row][col] = 9;
-
-                while !queue.is_empty() {
-                    let (r, c) = queue.pop_front().unwrap();
-                    basin_size += 1;
-                    if r > 0 && grid[r - 1][c] != 9 {
-                        grid[r - 1][c] = 9;
-                        queue.push_back((r - 1, c))
-                    }
-                    if r < n_rows - 1 && grid[r + 1][c] != 9 {
-                        grid[r + 1][c] = 9;
-                        queue.push_back((r + 1, c))
-                    }
-                    if c > 0 && grid[r][c - 1] != 9 {
-                        grid[r][c - 1] = 9;
-                        queue.push_back((r, c - 1))
-                    }
-                    if c < n_cols - 1 && grid[r][c + 1] != 9 {
-                        grid[r][c + 1] = 9;
-                        queue.push_back((r, c + 1))
-                    }
+            if grid[row][col] >= barrier {
+                ridges.push((row, col));
+                continue;
+            }
+
+            let root = sets.find(index(row, col));
+            let label = *root_labels[root].get_or_insert_with(|| {
+                basins.push(Basin {
+                    size: 0,
+                    low_point: (row, col),
+                    bounds: ((row, col), (row, col)),
+                });
+                basins.len() - 1
+            });
+            labels[row][col] = Some(label);
+
+            let basin = &mut basins[label];
+            basin.size += 1;
+
+            let (low_row, low_col) = basin.low_point;
+            if grid[row][col] < grid[low_row][low_col] {
+                basin.low_point = (row, col);
+            }
+
+            let ((top, left), (bottom, right)) = basin.bounds;
+            basin.bounds = ((top, left.min(col)), (bottom.max(row), right.max(col)));
+        }
+    }
+
+    Segmentation {
+        labels,
+        basins,
+        ridges,
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Flow {
+    Sink,
+    To((usize, usize)),
+}
+
+#[derive(Debug)]
+struct Watershed {
+    /// Where water on each cell flows next.
+    flow: Vec<Vec<Flow>>,
+    /// Index into `sinks` of the sink each cell eventually drains to.
+    basins: Vec<Vec<usize>>,
+    sinks: Vec<(usize, usize)>,
+    /// Number of cells draining to each sink.
+    catchments: Vec<usize>,
+}
+
+/// Trace where water falling on each cell ends up. Cells flow to their lowest
+/// strictly lower neighbour. Plateaus of equal height drain through the
+/// nearest cell on their edge that has somewhere lower to go, and a plateau
+/// with no way down is a single sink, represented by its first cell.
+fn watershed(grid: &[Vec<usize>], connectivity: Connectivity) -> Watershed {
+    let n_rows = grid.len();
+    let n_cols = grid[0].len();
+    let index = |row: usize, col: usize| row * n_cols + col;
+    let neighbours = |row: usize, col: usize| {
+        connectivity.offsets().iter().filter_map(move |(dr, dc)| {
+            let (r, c) = (row as isize + dr, col as isize + dc);
+            if r < 0 || c < 0 || r >= n_rows as isize || c >= n_cols as isize {
+                None
+            } else {
+                Some((r as usize, c as usize))
+            }
+        })
+    };
+
+    let mut plateaus = UnionFind::new(n_rows * n_cols);
+    for row in 0..n_rows {
+        for col in 0..n_cols {
+            for (r, c) in neighbours(row, col) {
+                if grid[r][c] == grid[row][col] {
+                    plateaus.union(index(row, col), index(r, c));
                 }
+            }
+        }
+    }
+
+    let mut flow = vec![vec![Flow::Sink; n_cols]; n_rows];
+    let mut reached = vec![false; n_rows * n_cols];
+    let mut has_outlet = vec![false; n_rows * n_cols];
+    let mut queue = VecDeque::new();
+
+    for row in 0..n_rows {
+        for col in 0..n_cols {
+            let lowest = neighbours(row, col).min_by_key(|&(r, c)| grid[r][c]);
+            if let Some((r, c)) = lowest.filter(|&(r, c)| grid[r][c] < grid[row][col]) {
+                flow[row][col] = Flow::To((r, c));
+                reached[index(row, col)] = true;
+                has_outlet[plateaus.find(index(row, col))] = true;
+                queue.push_back((row, col));
+            }
+        }
+    }
 
-                basin_sizes.push(basin_size);
+    let mut sinks = vec![];
+    let mut sink_ids = vec![None; n_rows * n_cols];
+    for row in 0..n_rows {
+        for col in 0..n_cols {
+            let root = plateaus.find(index(row, col));
+            if !has_outlet[root] && sink_ids[root].is_none() {
+                sink_ids[root] = Some(sinks.len());
+                sinks.push((row, col));
+                reached[index(row, col)] = true;
+                queue.push_back((row, col));
             }
         }
     }
 
-    basin_sizes.sort_by(|a, b| b.cmp(a));
-    basin_sizes[0..3].iter().product()
+    // Spread across each plateau from its outlets or sink
+    while let Some((row, col)) = queue.pop_front() {
+        for (r, c) in neighbours(row, col) {
+            if grid[r][c] == grid[row][col] && !reached[index(r, c)] {
+                reached[index(r, c)] = true;
+                flow[r][c] = Flow::To((row, col));
+                queue.push_back((r, c));
+            }
+        }
+    }
+
+    let mut basins = vec![vec![usize::MAX; n_cols]; n_rows];
+    for (i, &(row, col)) in sinks.iter().enumerate() {
+        basins[row][col] = i;
+    }
+
+    let mut catchments = vec![0; sinks.len()];
+    for row in 0..n_rows {
+        for col in 0..n_cols {
+            let mut path = vec![];
+            let mut cell = (row, col);
+            while basins[cell.0][cell.1] == usize::MAX {
+                path.push(cell);
+                if let Flow::To(next) = flow[cell.0][cell.1] {
+                    cell = next;
+                }
+            }
+
+            let basin = basins[cell.0][cell.1];
+            for (r, c) in path {
+                basins[r][c] = basin;
+            }
+            catchments[basin] += 1;
+        }
+    }
+
+    Watershed {
+        flow,
+        basins,
+        sinks,
+        catchments,
+    }
+}
+
+/// Sum the risk levels of the low points, where a flat-bottomed plateau counts
+/// once as a single low point rather than being skipped like in `parse1`.
+fn low_point_risk(grid: &[Vec<usize>], watershed: &Watershed) -> usize {
+    watershed
+        .sinks
+        .iter()
+        .map(|&(row, col)| grid[row][col] + 1)
+        .sum()
 }
 
 #[cfg(test)]
@@ -121,4 +379,75 @@ mod test {
     fn second() {
         assert_eq!(parse2(INPUTS), 1134);
     }
+
+    #[test]
+    fn segmentation() {
+        let grid = parse_grid(INPUTS);
+        let segmentation = segment(&grid, 9, Connectivity::Four);
+
+        assert_eq!(segmentation.basins.len(), 4);
+        assert_eq!(
+            segmentation.basins[0],
+            Basin {
+                size: 3,
+                low_point: (0, 1),
+                bounds: ((0, 0), (1, 1)),
+            }
+        );
+        assert_eq!(
+            segmentation.basins[1],
+            Basin {
+                size: 9,
+                low_point: (0, 9),
+                bounds: ((0, 5), (2, 9)),
+            }
+        );
+        assert_eq!(segmentation.labels[0][0], Some(0));
+        assert_eq!(segmentation.labels[0][2], None);
+        assert_eq!(segmentation.labels[4][9], Some(3));
+        assert_eq!(segmentation.ridges.len(), 50 - 3 - 9 - 14 - 9);
+
+        // Diagonal steps through gaps in the ridges join every basin
+        let segmentation = segment(&grid, 9, Connectivity::Eight);
+        assert_eq!(segmentation.basins.len(), 1);
+
+        // A lower barrier trims the 8s off the edges of the basins
+        let segmentation = segment(&grid, 8, Connectivity::Four);
+        let sizes: Vec<usize> = segmentation.basins.iter().map(|b| b.size).collect();
+        assert_eq!(sizes, vec![3, 9, 7, 6]);
+    }
+
+    #[test]
+    fn flow() {
+        let grid = parse_grid(INPUTS);
+        let watershed = watershed(&grid, Connectivity::Four);
+
+        assert_eq!(watershed.sinks, vec![(0, 1), (0, 9), (2, 2), (4, 6)]);
+        assert_eq!(watershed.catchments.iter().sum::<usize>(), 50);
+        assert_eq!(watershed.flow[0][0], Flow::To((0, 1)));
+        assert_eq!(watershed.flow[0][1], Flow::Sink);
+        assert_eq!(watershed.basins[4][9], 3);
+        assert_eq!(low_point_risk(&grid, &watershed), 15);
+    }
+
+    #[test]
+    fn plateaus() {
+        let input = "5555
+5225
+5555
+3444";
+        let grid = parse_grid(input);
+        let watershed = watershed(&grid, Connectivity::Four);
+
+        // The plateau of 2s is a sink even though neither cell is strictly
+        // lower than its neighbours
+        assert_eq!(watershed.sinks, vec![(1, 1), (3, 0)]);
+        assert_eq!(watershed.flow[1][2], Flow::To((1, 1)));
+        assert_eq!(low_point_risk(&grid, &watershed), 7);
+
+        // The 4s drain along their plateau towards the 3
+        assert_eq!(watershed.flow[3][3], Flow::To((3, 2)));
+        assert_eq!(watershed.flow[3][1], Flow::To((3, 0)));
+        assert_eq!(watershed.basins[3][3], 1);
+    }
 }
@@ -23,6 +23,7 @@ mod day6;
 mod day7;
 mod day8;
 mod day9;
+mod ocr;
 
 fn main() {
     day1::run();
@@ -0,0 +1,204 @@
+use std::fmt;
+
+/// The 4x6 block letters used by most puzzles, drawn with one blank column
+/// between letters.
+const SMALL: &[(char, [&str; 6])] = &[
+    ('A', [".##.", "#..#", "#..#", "####", "#..#", "#..#"]),
+    ('B', ["###.", "#..#", "###.", "#..#", "#..#", "###."]),
+    ('C', [".##.", "#..#", "#...", "#...", "#..#", ".##."]),
+    ('E', ["####", "#...", "###.", "#...", "#...", "####"]),
+    ('F', ["####", "#...", "###.", "#...", "#...", "#..."]),
+    ('G', [".##.", "#..#", "#...", "#.##", "#..#", ".###"]),
+    ('H', ["#..#", "#..#", "####", "#..#", "#..#", "#..#"]),
+    ('I', [".###", "..#.", "..#.", "..#.", "..#.", ".###"]),
+    ('J', ["..##", "...#", "...#", "...#", "#..#", ".##."]),
+    ('K', ["#..#", "#.#.", "##..", "#.#.", "#.#.", "#..#"]),
+    ('L', ["#...", "#...", "#...", "#...", "#...", "####"]),
+    ('O', [".##.", "#..#", "#..#", "#..#", "#..#", ".##."]),
+    ('P', ["###.", "#..#", "#..#", "###.", "#...", "#..."]),
+    ('R', ["###.", "#..#", "#..#", "###.", "#.#.", "#..#"]),
+    ('S', [".###", "#...", "#...", ".##.", "...#", "###."]),
+    ('U', ["#..#", "#..#", "#..#", "#..#", "#..#", ".##."]),
+    ('Y', ["#...", "#...", ".#.#", "..#.", "..#.", "..#."]),
+    ('Z', ["####", "...#", "..#.", ".#..", "#...", "####"]),
+];
+
+/// The larger 6x10 letters, drawn with two blank columns between letters.
+#[rustfmt::skip]
+const LARGE: &[(char, [&str; 10])] = &[
+    ('A', ["..##..", ".#..#.", "#....#", "#....#", "#....#", "######", "#....#", "#....#", "#....#", "#....#"]),
+    ('B', ["#####.", "#....#", "#....#", "#....#", "#####.", "#....#", "#....#", "#....#", "#....#", "#####."]),
+    ('C', [".####.", "#....#", "#.....", "#.....", "#.....", "#.....", "#.....", "#.....", "#....#", ".####."]),
+    ('E', ["######", "#.....", "#.....", "#.....", "#####.", "#.....", "#.....", "#.....", "#.....", "######"]),
+    ('F', ["######", "#.....", "#.....", "#.....", "#####.", "#.....", "#.....", "#.....", "#.....", "#....."]),
+    ('G', [".####.", "#....#", "#.....", "#.....", "#.....", "#..###", "#....#", "#....#", "#...##", ".###.#"]),
+    ('H', ["#....#", "#....#", "#....#", "#....#", "######", "#....#", "#....#", "#....#", "#....#", "#....#"]),
+    ('J', ["...###", "....#.", "....#.", "....#.", "....#.", "....#.", "....#.", "#...#.", "#...#.", ".###.."]),
+    ('K', ["#....#", "#...#.", "#..#..", "#.#...", "##....", "##....", "#.#...", "#..#..", "#...#.", "#....#"]),
+    ('L', ["#.....", "#.....", "#.....", "#.....", "#.....", "#.....", "#.....", "#.....", "#.....", "######"]),
+    ('N', ["#....#", "##...#", "##...#", "#.#..#", "#.#..#", "#..#.#", "#..#.#", "#...##", "#...##", "#....#"]),
+    ('P', ["#####.", "#....#", "#....#", "#....#", "#####.", "#.....", "#.....", "#.....", "#.....", "#....."]),
+    ('R', ["#####.", "#....#", "#....#", "#....#", "#####.", "#..#..", "#...#.", "#...#.", "#....#", "#....#"]),
+    ('X', ["#....#", "#....#", ".#..#.", ".#..#.", "..##..", "..##..", ".#..#.", ".#..#.", "#....#", "#....#"]),
+    ('Z', ["######", ".....#", ".....#", "....#.", "...#..", "..#...", ".#....", "#.....", "#.....", "######"]),
+];
+
+/// A glyph that isn't in the font, and where it appeared in the text.
+#[derive(Debug, PartialEq)]
+pub(crate) struct UnknownGlyph {
+    pub(crate) index: usize,
+    pub(crate) bitmap: Vec<String>,
+}
+
+impl fmt::Display for UnknownGlyph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "unknown glyph at position {}:", self.index)?;
+        for row in &self.bitmap {
+            writeln!(f, "{}", row)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read block letters from a grid of dots, indexed `[row][col]` with the
+/// first letter starting in the top left corner. The font is chosen by the
+/// height of the text: up to 6 rows is the small font, up to 10 the large.
+pub(crate) fn recognise(dots: &[Vec<bool>]) -> Result<String, Vec<UnknownGlyph>> {
+    let height = dots
+        .iter()
+        .rposition(|row| row.iter().any(|&dot| dot))
+        .map_or(0, |last| last + 1);
+    let width = dots
+        .iter()
+        .filter_map(|row| row.iter().rposition(|&dot| dot))
+        .max()
+        .map_or(0, |last| last + 1);
+
+    if height <= 6 {
+        read(dots, width, SMALL, 4, 1)
+    } else {
+        read(dots, width, LARGE, 6, 2)
+    }
+}
+
+fn read<const H: usize>(
+    dots: &[Vec<bool>],
+    width: usize,
+    font: &[(char, [&str; H])],
+    glyph_width: usize,
+    gap: usize,
+) -> Result<String, Vec<UnknownGlyph>> {
+    let stride = glyph_width + gap;
+    let count = width.div_ceil(stride);
+    let mut text = String::new();
+    let mut unknown = vec![];
+
+    for index in 0..count {
+        // Each glyph keeps the gap after it, and the last one takes whatever
+        // is left, so that stray dots show up in the bitmap
+        let start = index * stride;
+        let end = if index + 1 == count {
+            width.max(start + glyph_width)
+        } else {
+            start + stride
+        };
+        let bitmap: Vec<String> = (0..H)
+            .map(|row| {
+                (start..end)
+                    .map(|col| {
+                        let dot = dots.get(row).and_then(|r| r.get(col)).copied();
+                        if dot.unwrap_or(false) {
+                            '#'
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let matches = |glyph: &[&str; H]| {
+            glyph.iter().zip(&bitmap).all(|(glyph_row, row)| {
+                row.starts_with(glyph_row) && row[glyph_width..].chars().all(|c| c == '.')
+            })
+        };
+        match font.iter().find(|(_, glyph)| matches(glyph)) {
+            Some(&(c, _)) => text.push(c),
+            None => unknown.push(UnknownGlyph { index, bitmap }),
+        }
+    }
+
+    if unknown.is_empty() {
+        Ok(text)
+    } else {
+        Err(unknown)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn grid(rows: &[&str]) -> Vec<Vec<bool>> {
+        rows.iter()
+            .map(|row| row.chars().map(|c| c == '#').collect())
+            .collect()
+    }
+
+    #[test]
+    fn small() {
+        let dots = grid(&[
+            "###...##..####.#....###..#..#.####.###..",
+            "#..#.#..#....#.#....#..#.#..#.#....#..#.",
+            "#..#.#......#..#....###..####.###..#..#.",
+            "###..#.##..#...#....#..#.#..#.#....###..",
+            "#.#..#..#.#....#....#..#.#..#.#....#....",
+            "#..#..###.####.####.###..#..#.#....#....",
+        ]);
+        assert_eq!(recognise(&dots), Ok("RGZLBHFP".to_string()));
+    }
+
+    #[test]
+    fn large() {
+        let dots = grid(&[
+            "#....#..######",
+            "#....#..#.....",
+            "#....#..#.....",
+            "#....#..#.....",
+            "######..#####.",
+            "#....#..#.....",
+            "#....#..#.....",
+            "#....#..#.....",
+            "#....#..#.....",
+            "#....#..######",
+        ]);
+        assert_eq!(recognise(&dots), Ok("HE".to_string()));
+    }
+
+    #[test]
+    fn unknown() {
+        let dots = grid(&[
+            "#..#.#...#",
+            "#..#.##.##",
+            "####.#.#.#",
+            "#..#.#...#",
+            "#..#.#...#",
+            "#..#.#...#",
+        ]);
+        assert_eq!(
+            recognise(&dots),
+            Err(vec![UnknownGlyph {
+                index: 1,
+                bitmap: ["#...#", "##.##", "#.#.#", "#...#", "#...#", "#...#"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+            }])
+        );
+
+        // A dot in the gap after a letter spoils it
+        let dots = grid(&["#..##", "#..#.", "####.", "#..#.", "#..#.", "#..#."]);
+        assert_eq!(recognise(&dots).unwrap_err()[0].bitmap[0], "#..##");
+    }
+}